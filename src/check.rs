@@ -0,0 +1,181 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// Validate a config file and the environment it is to be run in, without
+// creating a tunnel.
+
+use config::Config;
+use error::TiTunError;
+use script_runner::ScriptRunner;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Result of one check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl Check {
+    fn ok<S: Into<String>>(message: S) -> Check {
+        Check {
+            ok: true,
+            message: message.into(),
+        }
+    }
+
+    fn fail<S: Into<String>>(message: S) -> Check {
+        Check {
+            ok: false,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", if self.ok { "[ OK ]" } else { "[FAIL]" }, self.message)
+    }
+}
+
+/// Check the config file at `path`, and whether a tunnel could be created.
+pub fn check(path: &Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let config = File::open(path)
+        .and_then(|mut f| {
+            let mut s = String::new();
+            f.read_to_string(&mut s).map(|_| s)
+        })
+        .map_err(TiTunError::from)
+        .and_then(|s| Ok(Config::parse_strict(&s)?));
+    match config {
+        Ok(c) => {
+            checks.push(Check::ok(format!("{}: valid config", path.display())));
+            check_config(&c, &mut checks);
+        }
+        Err(e) => checks.push(Check::fail(format!("{}: {}", path.display(), e))),
+    }
+
+    checks.push(match OpenOptions::new().read(true).write(true).open("/dev/net/tun") {
+        Ok(_) => Check::ok("/dev/net/tun is accessible"),
+        Err(e) => Check::fail(format!("/dev/net/tun: {}", e)),
+    });
+
+    let mut status = String::new();
+    let caps = File::open("/proc/self/status")
+        .and_then(|mut f| f.read_to_string(&mut status))
+        .ok()
+        .and_then(|_| effective_caps(&status));
+    checks.push(match caps {
+        Some(c) if c & (1 << CAP_NET_ADMIN) != 0 => Check::ok("CAP_NET_ADMIN is available"),
+        Some(_) => Check::fail("CAP_NET_ADMIN is not available, run as root or grant it"),
+        None => Check::fail("cannot determine capabilities from /proc/self/status"),
+    });
+
+    checks
+}
+
+fn check_config(c: &Config, checks: &mut Vec<Check>) {
+    if let Some(p) = c.peer {
+        checks.push(Check::ok(format!("peer resolves to {}", p)));
+    }
+    match c.bind.len() {
+        0 => {}
+        n if n <= 4 => {
+            let addrs: Vec<_> = c.bind.iter().map(|b| b.to_string()).collect();
+            checks.push(Check::ok(format!("bind resolves to {}", addrs.join(", "))));
+        }
+        n => {
+            checks.push(Check::ok(format!("bind resolves to {} addresses, {} to {}",
+                                          n,
+                                          c.bind[0],
+                                          c.bind[n - 1])));
+        }
+    }
+
+    let scripts = [("on_up", &c.on_up),
+                   ("on_down", &c.on_down),
+                   ("on_pmtu_change", &c.on_pmtu_change)];
+    for &(name, script) in &scripts {
+        if let Some(ref s) = *script {
+            checks.push(match ScriptRunner::new().check(s.as_bytes()) {
+                Ok(_) => Check::ok(format!("{}: valid shell syntax", name)),
+                Err(e) => Check::fail(format!("{}: {}", name, e)),
+            });
+        }
+    }
+}
+
+/// Parse the effective capability set from the content of
+/// `/proc/<pid>/status`.
+fn effective_caps(status: &str) -> Option<u64> {
+    status.lines()
+        .find(|l| l.starts_with("CapEff:"))
+        .and_then(|l| u64::from_str_radix(l["CapEff:".len()..].trim(), 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks() {
+        assert_eq!(effective_caps("Name:\ttitun\nCapEff:\t0000000000001000\n"),
+                   Some(1 << CAP_NET_ADMIN));
+        assert_eq!(effective_caps("Name:\ttitun\n"), None);
+
+        let c = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+on_up: "ip link set $TUN up"
+on_down: "if true; then"
+"#)
+            .unwrap();
+        let mut checks = Vec::new();
+        check_config(&c, &mut checks);
+        assert_eq!(checks.len(), 3);
+        assert!(checks[0].ok && checks[0].message.contains("127.0.0.1:3000"));
+        assert!(checks[1].ok);
+        assert!(!checks[2].ok && checks[2].message.starts_with("on_down: "));
+
+        let unknown = r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+mtuu: 1400
+"#;
+        assert!(Config::parse(unknown).is_ok());
+        assert!(Config::parse_strict(unknown).is_err());
+
+        // Configs that `run` would reject are rejected when parsing.
+        for extra in &["outer_mtu: 80\nframing: dtls",
+                       "cover_rate: 10\ncover_size: 100",
+                       "mtu: 1500\nouter_mtu: 100\nfragment: true",
+                       "mtu: 40",
+                       "dev_name: \"a very long name\""] {
+            let s = format!("peer: \"127.0.0.1:3000\"\n\
+                             key: \"Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=\"\n{}",
+                            extra);
+            assert!(Config::parse_strict(&s).is_err(), "{}", extra);
+        }
+    }
+}
@@ -15,30 +15,86 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
-use crypto::DEFAULT_MAX_DIFF;
+use crypto::{DEFAULT_MAX_DIFF, DEFAULT_PWHASH_MEM, DEFAULT_PWHASH_OPS, DEFAULT_PWHASH_SALT,
+             MAX_PWHASH_MEM, OVERHEAD, derive_key, dh_key, gen_private_key, public_key};
 use data_encoding::base64;
 use error::Result;
+use fec;
+use framing::Framer;
+use message;
+use mtu::{self, tun_mtu};
 use serde_yaml as yaml;
+use sodiumoxide::crypto::scalarmult::curve25519::{GroupElement, Scalar};
 use sodiumoxide::crypto::secretbox::{Key, gen_key};
-use std::convert::From;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use yaml_rust::{ScanError, YamlLoader};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
 
 #[derive(Serialize, Deserialize)]
 struct Config1 {
-    pub bind: Option<String>,
+    pub bind: Option<yaml::Value>,
     pub peer: Option<String>,
-    pub key: String,
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    pub key_env: Option<String>,
+    pub key_command: Option<String>,
+    pub key_credential: Option<String>,
+    pub passphrase: Option<String>,
+    pub passphrase_file: Option<String>,
+    pub passphrase_salt: Option<String>,
+    pub passphrase_ops: Option<u64>,
+    pub passphrase_mem: Option<u64>,
+    pub private_key: Option<String>,
+    pub private_key_file: Option<String>,
+    pub public_key: Option<String>,
     pub on_up: Option<String>,
     pub on_down: Option<String>,
     pub bufsize: Option<usize>,
     pub max_diff: Option<u64>,
     pub dev_name: Option<String>,
+    pub scheduler: Option<bool>,
+    pub priority_ports: Option<Vec<String>>,
+    pub queue_limit: Option<usize>,
+    pub copy_dscp: Option<bool>,
+    pub ecn: Option<bool>,
+    pub address: Option<String>,
+    pub peer_address: Option<String>,
+    pub mtu: Option<u32>,
+    pub outer_mtu: Option<u32>,
+    pub routes: Option<Vec<String>>,
+    pub up: Option<bool>,
+    pub pmtud: Option<bool>,
+    pub on_pmtu_change: Option<String>,
+    pub mss_clamp: Option<yaml::Value>,
+    pub fragment: Option<bool>,
+    pub fec_group: Option<u8>,
+    pub fec_parity: Option<u8>,
+    pub compress: Option<bool>,
+    pub padding: Option<yaml::Value>,
+    pub cover_rate: Option<u32>,
+    pub cover_size: Option<u32>,
+    pub framing: Option<String>,
+    pub rotate_port: Option<u64>,
+    pub rotate_port_idle: Option<u64>,
+    pub metrics_listen: Option<String>,
+    pub keepalive: Option<u64>,
+    pub strict: Option<bool>,
 }
 
 /// One of bind / peer must be set.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Config {
-    pub bind: Option<SocketAddr>,
+    /// Addresses to listen on.
+    pub bind: Vec<SocketAddr>,
     pub peer: Option<SocketAddr>,
     pub key: Key,
     pub on_up: Option<String>,
@@ -46,47 +102,850 @@ pub struct Config {
     pub bufsize: usize,
     pub max_diff: u64,
     pub dev_name: Option<String>,
+    /// Prioritize interactive traffic instead of sending in arrival order.
+    pub scheduler: bool,
+    /// Inclusive port ranges whose traffic is considered interactive.
+    pub priority_ports: Vec<(u16, u16)>,
+    /// Maximum number of packets queued for sending.
+    pub queue_limit: usize,
+    /// Copy DSCP of inner packets to outer packets.
+    pub copy_dscp: bool,
+    /// Propagate ECN between inner and outer packets as in RFC 6040.
+    pub ecn: bool,
+    /// Address and prefix length of the tun device.
+    pub address: Option<(IpAddr, u8)>,
+    /// Point-to-point peer address of the tun device.
+    pub peer_address: Option<IpAddr>,
+    /// MTU of the tun device. Computed from `outer_mtu` if not set.
+    pub mtu: Option<u32>,
+    /// MTU of the path to the peer.
+    pub outer_mtu: u32,
+    /// Routes via the tun device, as destination and prefix length.
+    pub routes: Vec<(IpAddr, u8)>,
+    /// Set the tun device up.
+    pub up: bool,
+    /// Discover path MTU to the peer, up to `outer_mtu`.
+    pub pmtud: bool,
+    /// Script run when the discovered path MTU changes.
+    pub on_pmtu_change: Option<String>,
+    /// Clamp MSS of TCP SYN packets.
+    pub mss_clamp: Option<MssClamp>,
+    /// Fragment packets that do not fit in the path MTU.
+    pub fragment: bool,
+    /// Forward error correction, as numbers of data and parity packets per
+    /// group.
+    pub fec: Option<(u8, u8)>,
+    /// Compress packets with LZ4.
+    pub compress: bool,
+    /// Pad packets to hide their length.
+    pub padding: Option<Padding>,
+    /// Send packets at this constant rate, per second, filling gaps with
+    /// dummy packets.
+    pub cover_rate: Option<u32>,
+    /// Size of cover traffic packets, as UDP payload.
+    pub cover_size: Option<u32>,
+    /// Outer framing of packets.
+    pub framing: Option<Framing>,
+    /// Re-bind to a new random port every this many seconds.
+    pub rotate_port: Option<u64>,
+    /// Re-bind to a new random port if nothing is received from the peer
+    /// this many seconds after sending something.
+    pub rotate_port_idle: Option<u64>,
+    /// Serve Prometheus metrics over HTTP on this address.
+    pub metrics_listen: Option<SocketAddr>,
+    /// Send a keepalive packet to the peer if nothing has been sent for this
+    /// many seconds.
+    pub keepalive: Option<u64>,
 }
 
-fn to_socket_addr(s: &str) -> Result<SocketAddr> {
-    for a in s.to_socket_addrs()? {
-        return Ok(a);
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MssClamp {
+    /// Derive from the tunnel MTU.
+    Auto,
+    Fixed(u16),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Padding {
+    /// Pad every packet to the tunnel MTU.
+    Mtu,
+    /// Pad to the smallest of these sizes that fits.
+    Buckets(Vec<u16>),
+    /// Random amount of padding, up to the tunnel MTU.
+    Random,
+}
+
+/// Outer framing, to make packets look like some other protocol.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Framing {
+    /// DTLS 1.2 application data records.
+    Dtls,
+    /// QUIC short header packets.
+    Quic,
+    /// RTP packets.
+    Rtp,
+}
+
+/// Maximum number of addresses to bind to.
+const MAX_BIND: usize = 1024;
+
+/// Options that may appear in a config file.
+const OPTIONS: &'static [&'static str] =
+    &["bind", "peer", "key", "key_file", "key_env", "key_command", "key_credential",
+      "passphrase", "passphrase_file", "passphrase_salt", "passphrase_ops", "passphrase_mem",
+      "private_key", "private_key_file", "public_key",
+      "on_up", "on_down", "bufsize", "max_diff", "dev_name", "scheduler",
+      "priority_ports", "queue_limit", "copy_dscp", "ecn", "address", "peer_address", "mtu",
+      "outer_mtu", "routes", "up", "pmtud", "on_pmtu_change", "mss_clamp", "fragment",
+      "fec_group", "fec_parity", "compress", "padding", "cover_rate", "cover_size", "framing",
+      "rotate_port", "rotate_port_idle", "metrics_listen", "keepalive", "strict"];
+
+/// Position in a config file. Both start from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {} column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Not a valid YAML mapping.
+    Syntax {
+        message: String,
+        location: Option<Location>,
+    },
+    /// An unknown option, in strict mode.
+    Unknown { key: String, location: Option<Location> },
+    /// A required option is not set.
+    Missing { message: String },
+    /// An option has an invalid value, or does not go with other options.
+    Invalid {
+        key: String,
+        message: String,
+        location: Option<Location>,
+    },
+    /// A host name can not be resolved.
+    Resolve {
+        key: String,
+        host: String,
+        message: String,
+        location: Option<Location>,
+    },
+}
+
+impl ConfigError {
+    /// The option concerned, if any.
+    pub fn key(&self) -> Option<&str> {
+        match *self {
+            ConfigError::Unknown { ref key, .. } |
+            ConfigError::Invalid { ref key, .. } |
+            ConfigError::Resolve { ref key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        match *self {
+            ConfigError::Syntax { location, .. } |
+            ConfigError::Unknown { location, .. } |
+            ConfigError::Invalid { location, .. } |
+            ConfigError::Resolve { location, .. } => location,
+            ConfigError::Missing { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Config: ")?;
+        if let Some(l) = self.location() {
+            write!(f, "{}: ", l)?;
+        }
+        match *self {
+            ConfigError::Syntax { ref message, .. } |
+            ConfigError::Missing { ref message } |
+            ConfigError::Invalid { ref message, .. } => write!(f, "{}", message),
+            ConfigError::Unknown { ref key, .. } => write!(f, "unknown option `{}`", key),
+            ConfigError::Resolve { ref key, ref host, ref message, .. } => {
+                write!(f, "`{}`: cannot resolve `{}`: {}", key, host, message)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Syntax { .. } => "invalid config syntax",
+            ConfigError::Unknown { .. } => "unknown config option",
+            ConfigError::Missing { .. } => "missing config option",
+            ConfigError::Invalid { .. } => "invalid config option",
+            ConfigError::Resolve { .. } => "cannot resolve host in config",
+        }
+    }
+}
+
+/// Error in a single value, without knowing which option it is for.
+enum ValueError {
+    Invalid(String),
+    Resolve(String, String),
+}
+
+impl ValueError {
+    fn at(self, key: &str, src: &str) -> ConfigError {
+        match self {
+            ValueError::Invalid(message) => {
+                ConfigError::Invalid {
+                    key: key.to_string(),
+                    message: format!("`{}`: {}", key, message),
+                    location: locate_value(src, key),
+                }
+            }
+            ValueError::Resolve(host, message) => {
+                ConfigError::Resolve {
+                    key: key.to_string(),
+                    host: host,
+                    message: message,
+                    location: locate_value(src, key),
+                }
+            }
+        }
+    }
+}
+
+/// Positions of the top level options in a config file: the name of each
+/// option, where it is and where its value is.
+#[derive(Default)]
+struct Positions {
+    depth: usize,
+    in_value: bool,
+    key: Option<(String, Marker)>,
+    found: Vec<(String, Marker, Marker)>,
+}
+
+impl MarkedEventReceiver for Positions {
+    fn on_event(&mut self, ev: &Event, mark: Marker) {
+        let node = match *ev {
+            Event::Scalar(..) |
+            Event::Alias(_) |
+            Event::SequenceStart(_) |
+            Event::MappingStart(_) => true,
+            _ => false,
+        };
+        if node && self.depth == 1 {
+            if self.in_value {
+                if let Some((key, at)) = self.key.take() {
+                    self.found.push((key, at, mark));
+                }
+            } else if let Event::Scalar(ref key, ..) = *ev {
+                self.key = Some((key.clone(), mark));
+            }
+            self.in_value = !self.in_value;
+        }
+        match *ev {
+            Event::SequenceStart(_) |
+            Event::MappingStart(_) => self.depth += 1,
+            Event::SequenceEnd |
+            Event::MappingEnd => self.depth -= 1,
+            _ => {}
+        }
+    }
+}
+
+/// Find where a top level option (or its value, if `value`) is in the config
+/// file. If the option is repeated, the last one is used, like the parser does.
+fn find(src: &str, key: &str, value: bool) -> Option<Location> {
+    let mut positions = Positions::default();
+    if Parser::new(src.chars()).load(&mut positions, false).is_err() {
+        return None;
+    }
+    positions.found
+        .into_iter()
+        .filter(|&(ref k, _, _)| k == key)
+        .last()
+        .and_then(|(_, k, v)| marker_location(if value { v } else { k }))
+}
+
+/// Find where a top level option is in the config file.
+fn locate(src: &str, key: &str) -> Option<Location> {
+    find(src, key, false)
+}
+
+/// Find where the value of a top level option is in the config file.
+fn locate_value(src: &str, key: &str) -> Option<Location> {
+    find(src, key, true)
+}
+
+/// The parser only tells positions in error messages, as
+/// `... at line L column C`. Split such a message into the message and the
+/// position.
+fn split_position(e: &str) -> (String, Option<Location>) {
+    let mut parts = e.rsplitn(2, " at line ");
+    let position = parts.next().unwrap();
+    let message = parts.next().unwrap_or(position).to_string();
+    let mut nums = position.split(" column ").map(|n| n.trim().parse::<usize>());
+    let location = match (nums.next(), nums.next()) {
+        (Some(Ok(line)), Some(Ok(column))) => {
+            Some(Location {
+                line: line,
+                column: column,
+            })
+        }
+        _ => None,
+    };
+    (message, location)
+}
+
+fn marker_location(mark: Marker) -> Option<Location> {
+    split_position(&ScanError::new(mark, "").to_string()).1
+}
+
+/// Position of a YAML syntax error.
+fn syntax_error(src: &str) -> Option<ConfigError> {
+    let e = match YamlLoader::load_from_str(src) {
+        Ok(_) => return None,
+        Err(e) => e.to_string(),
+    };
+    let (message, location) = split_position(&e);
+    Some(ConfigError::Syntax {
+        message: message,
+        location: location,
+    })
+}
+
+/// Find the option that failed to deserialize, by deserializing the options
+/// one at a time.
+fn type_error(src: &str, m: &yaml::Mapping, e: yaml::Error) -> ConfigError {
+    let e = e.to_string();
+    if e.starts_with("missing field") {
+        return ConfigError::Missing { message: e };
+    }
+    let key = yaml::Value::String("key".to_string());
+    let mut keys: Vec<_> = m.keys().filter(|k| **k != key).collect();
+    keys.insert(0, &key);
+    for k in keys {
+        let mut single = m.clone();
+        let others: Vec<_> = m.keys().filter(|o| *o != k && **o != key).cloned().collect();
+        for o in others {
+            single.remove(&o);
+        }
+        if let Err(e) = yaml::from_value::<Config1>(yaml::Value::Mapping(single)) {
+            let k = k.as_str().unwrap();
+            return ConfigError::Invalid {
+                key: k.to_string(),
+                message: format!("`{}`: {}", k, e),
+                location: locate_value(src, k),
+            };
+        }
+    }
+    ConfigError::Syntax {
+        message: e,
+        location: None,
+    }
+}
+
+/// Options that set the key, only one of which can be used.
+const KEY_OPTIONS: [&'static str; 7] = ["key",
+                                        "key_file",
+                                        "key_env",
+                                        "key_command",
+                                        "key_credential",
+                                        "passphrase",
+                                        "passphrase_file"];
+
+/// Smallest MTU of the tun device, the minimum IPv4 MTU.
+const MIN_PACKET_MTU: u32 = 68;
+
+
+/// Get the key: derived from `private_key` and the peer's `public_key` if
+/// set, using any of `KEY_OPTIONS` as a pre-shared key, otherwise from
+/// whichever of `KEY_OPTIONS` is set.
+fn load_key(c: &Config1, src: &str) -> ::std::result::Result<Key, ConfigError> {
+    let (option, private) = match load_private_key(c, src)? {
+        Some(p) => p,
+        None => {
+            if c.public_key.is_some() {
+                return Err(ValueError::Invalid("requires `private_key` or `private_key_file`"
+                        .to_string())
+                    .at("public_key", src));
+            }
+            return load_shared_key(c, src)?.ok_or_else(|| {
+                ConfigError::Missing {
+                    message: format!("one of {} or `private_key` must be specified",
+                                     KEY_OPTIONS.iter()
+                                         .map(|o| format!("`{}`", o))
+                                         .collect::<Vec<_>>()
+                                         .join(", ")),
+                }
+            });
+        }
+    };
+    let public = match c.public_key {
+        Some(ref p) => {
+            decode_public_key(p).ok_or_else(|| {
+                    ValueError::Invalid("public key is not 32 bytes of base64".to_string())
+                })
+                .map_err(|e| e.at("public_key", src))?
+        }
+        None => {
+            return Err(ConfigError::Missing {
+                message: format!("`public_key` of the peer must be specified with `{}`", option),
+            })
+        }
+    };
+    let psk = load_shared_key(c, src)?;
+    dh_key(&private, &public, psk.as_ref())
+        .ok_or_else(|| ValueError::Invalid("invalid public key".to_string()).at("public_key", src))
+}
+
+/// Get the private key from `private_key` or `private_key_file`.
+fn load_private_key(c: &Config1,
+                    src: &str)
+                    -> ::std::result::Result<Option<(&'static str, Scalar)>, ConfigError> {
+    let (option, encoded) = match (&c.private_key, &c.private_key_file) {
+        (&Some(_), &Some(_)) => {
+            return Err(ValueError::Invalid("cannot be used with `private_key`".to_string())
+                .at("private_key_file", src))
+        }
+        (&Some(ref k), &None) => ("private_key", Ok(k.clone())),
+        (&None, &Some(ref f)) => ("private_key_file", read_key_file(Path::new(f))),
+        (&None, &None) => return Ok(None),
+    };
+    let encoded = encoded.map_err(|e| ValueError::Invalid(e).at(option, src))?;
+    decode_private_key(&encoded)
+        .map(|k| Some((option, k)))
+        .ok_or_else(|| {
+            ValueError::Invalid("private key is not 32 bytes of base64".to_string()).at(option, src)
+        })
+}
+
+/// Get the key from whichever of `KEY_OPTIONS` is set, or `None` if none is.
+fn load_shared_key(c: &Config1, src: &str) -> ::std::result::Result<Option<Key>, ConfigError> {
+    let values = [&c.key,
+                  &c.key_file,
+                  &c.key_env,
+                  &c.key_command,
+                  &c.key_credential,
+                  &c.passphrase,
+                  &c.passphrase_file];
+    let set: Vec<_> = (0..KEY_OPTIONS.len()).filter(|&i| values[i].is_some()).collect();
+    if set.len() > 1 {
+        let option = KEY_OPTIONS[set[1]];
+        return Err(ValueError::Invalid(format!("cannot be used with `{}`", KEY_OPTIONS[set[0]]))
+            .at(option, src));
+    }
+    let option = set.first().map(|&i| KEY_OPTIONS[i]);
+    if option != Some("passphrase") && option != Some("passphrase_file") {
+        let pwhash_options = [("passphrase_salt", c.passphrase_salt.is_some()),
+                              ("passphrase_ops", c.passphrase_ops.is_some()),
+                              ("passphrase_mem", c.passphrase_mem.is_some())];
+        if let Some(&(o, _)) = pwhash_options.iter().find(|&&(_, set)| set) {
+            return Err(ValueError::Invalid("requires `passphrase` or `passphrase_file`"
+                    .to_string())
+                .at(o, src));
+        }
+    }
+    let option = match option {
+        Some(o) => o,
+        None => return Ok(None),
+    };
+    let value = values[set[0]].as_ref().unwrap();
+
+    let passphrase = match option {
+        "passphrase" => Some(value.clone()),
+        "passphrase_file" => {
+            let p = read_key_file(Path::new(value))
+                .map_err(|e| ValueError::Invalid(e).at(option, src))?;
+            Some(p.lines().next().unwrap_or("").to_string())
+        }
+        _ => None,
+    };
+    if let Some(passphrase) = passphrase {
+        return derive_passphrase_key(c, src, option, &passphrase).map(Some);
+    }
+
+    let encoded = match option {
+        "key" => Ok(value.clone()),
+        "key_file" => read_key_file(Path::new(value)),
+        "key_env" => env::var(value).map_err(|e| format!("`${}`: {}", value, e)),
+        "key_command" => run_key_command(value),
+        "key_credential" => {
+            match env::var_os("CREDENTIALS_DIRECTORY") {
+                Some(dir) => read_key_file(&Path::new(&dir).join(value)),
+                None => Err("`$CREDENTIALS_DIRECTORY` is not set".to_string()),
+            }
+        }
+        _ => unreachable!(),
+    };
+    let encoded = encoded.map_err(|e| ValueError::Invalid(e).at(option, src))?;
+    let k = base64::decode(encoded.trim().as_bytes()).map_err(|e| {
+            ValueError::Invalid(format!("key is not valid base64: {}", e)).at(option, src)
+        })?;
+    Key::from_slice(&k).map(Some).ok_or_else(|| {
+        ValueError::Invalid(format!("key must be 32 bytes, got {} bytes", k.len())).at(option, src)
+    })
+}
+
+/// Derive the key from a passphrase with Argon2id.
+fn derive_passphrase_key(c: &Config1,
+                         src: &str,
+                         option: &'static str,
+                         passphrase: &str)
+                         -> ::std::result::Result<Key, ConfigError> {
+    let ops = c.passphrase_ops.unwrap_or(DEFAULT_PWHASH_OPS);
+    if ops == 0 {
+        return Err(ValueError::Invalid("must be at least 1".to_string())
+            .at("passphrase_ops", src));
+    }
+    let mem = c.passphrase_mem.unwrap_or(DEFAULT_PWHASH_MEM);
+    if mem == 0 || mem > MAX_PWHASH_MEM {
+        return Err(ValueError::Invalid(format!("must be between 1 and {} (MiB)", MAX_PWHASH_MEM))
+            .at("passphrase_mem", src));
+    }
+    if passphrase.is_empty() {
+        return Err(ValueError::Invalid("passphrase is empty".to_string()).at(option, src));
+    }
+    let salt = c.passphrase_salt.as_ref().map_or(DEFAULT_PWHASH_SALT, |s| s.as_str());
+    derive_key(passphrase.as_bytes(), salt, ops, mem)
+        .ok_or_else(|| ValueError::Invalid("key derivation failed".to_string()).at(option, src))
+}
+
+/// Read a key file, refusing files that everyone can read.
+fn read_key_file(path: &Path) -> ::std::result::Result<String, String> {
+    let mut f = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mode = f.metadata().map_err(|e| format!("{}: {}", path.display(), e))?.permissions().mode();
+    if mode & 0o004 != 0 {
+        return Err(format!("{} is readable by everyone, refusing to use it", path.display()));
+    }
+    let mut s = String::new();
+    f.read_to_string(&mut s).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(s)
+}
+
+/// Run a shell command that prints the key.
+fn run_key_command(command: &str) -> ::std::result::Result<String, String> {
+    let out = Command::new("sh").arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !out.status.success() {
+        return Err(format!("command failed with {}", out.status));
+    }
+    String::from_utf8(out.stdout).map_err(|e| e.to_string())
+}
+
+fn to_socket_addr(s: &str) -> ::std::result::Result<SocketAddr, ValueError> {
+    match s.to_socket_addrs() {
+        Ok(mut addrs) => {
+            addrs.next().ok_or_else(|| ValueError::Resolve(s.to_string(), "no address".to_string()))
+        }
+        Err(e) => Err(ValueError::Resolve(s.to_string(), e.to_string())),
+    }
+}
+
+/// Parse `"1.2.3.4:5000"` or `"[::]:5000-5100"`.
+fn parse_bind(s: &str) -> ::std::result::Result<Vec<SocketAddr>, ValueError> {
+    let i = s.rfind(':').ok_or_else(|| ValueError::Invalid(format!("missing port in `{}`", s)))?;
+    let (lo, hi) = parse_port_range(&s[i + 1..])?;
+    let mut addr = to_socket_addr(&format!("{}:0", &s[..i]))?;
+    Ok((lo as u32..hi as u32 + 1)
+        .map(|p| {
+            addr.set_port(p as u16);
+            addr
+        })
+        .collect())
+}
+
+/// Parse `"22"` or `"5000-5100"`.
+fn parse_port_range(s: &str) -> ::std::result::Result<(u16, u16), ValueError> {
+    let mut parts = s.splitn(2, '-');
+    let lo = parts.next().unwrap().trim().parse::<u16>();
+    let hi = parts.next().map(|p| p.trim().parse::<u16>());
+    match (lo, hi) {
+        (Ok(lo), None) => Ok((lo, lo)),
+        (Ok(lo), Some(Ok(hi))) if lo <= hi => Ok((lo, hi)),
+        _ => Err(ValueError::Invalid(format!("invalid port range `{}`", s))),
     }
-    Err(From::from("cannot resolve host"))
 }
 
 impl Config {
-    pub fn parse(s: &str) -> Result<Config> {
-        let v: yaml::Value = yaml::from_str(s)?;
-        if let yaml::Value::Mapping(ref m) = v {
-            for k in m.keys() {
-                let k = k.as_str().unwrap();
-                match k {
-                    "bind" | "peer" | "key" | "on_up" | "on_down" | "bufsize" | "max_diff" |
-                    "dev_name" => {}
-                    _ => warn!("unknown config {}", k),
+    pub fn parse(s: &str) -> ::std::result::Result<Config, ConfigError> {
+        Config::parse1(s, false)
+    }
+
+    /// Like `parse`, but unknown options are errors instead of warnings. Also
+    /// enabled by `strict: true` in the config.
+    pub fn parse_strict(s: &str) -> ::std::result::Result<Config, ConfigError> {
+        Config::parse1(s, true)
+    }
+
+    fn parse1(s: &str, strict: bool) -> ::std::result::Result<Config, ConfigError> {
+        let v: yaml::Value = match yaml::from_str(s) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(syntax_error(s).unwrap_or_else(|| {
+                    ConfigError::Syntax {
+                        message: e.to_string(),
+                        location: None,
+                    }
+                }))
+            }
+        };
+        let m = match v {
+            yaml::Value::Mapping(ref m) => m,
+            _ => {
+                return Err(ConfigError::Syntax {
+                    message: "config must be a mapping".to_string(),
+                    location: None,
+                })
+            }
+        };
+        let strict = strict || m.get(&yaml::Value::String("strict".to_string())) ==
+                               Some(&yaml::Value::Bool(true));
+        for k in m.keys() {
+            let k = match k.as_str() {
+                Some(k) => k,
+                None => {
+                    return Err(ConfigError::Syntax {
+                        message: "option names must be strings".to_string(),
+                        location: None,
+                    })
+                }
+            };
+            if !OPTIONS.contains(&k) {
+                if strict {
+                    return Err(ConfigError::Unknown {
+                        key: k.to_string(),
+                        location: locate(s, k),
+                    });
                 }
+                warn!("unknown config {}", k);
             }
         }
-        let c: Config1 = yaml::from_value(v)?;
+        let c: Config1 = match yaml::from_value(v.clone()) {
+            Ok(c) => c,
+            Err(e) => return Err(type_error(s, m, e)),
+        };
 
-        let key = decode_key(&c.key).ok_or_else(|| "Config: Failed to decode key")?;
+        let invalid = |key: &str, message: &str| {
+            ConfigError::Invalid {
+                key: key.to_string(),
+                message: message.to_string(),
+                location: locate_value(s, key),
+            }
+        };
+
+        let key = load_key(&c, s)?;
 
         if c.peer.is_none() && c.bind.is_none() {
-            return Err(From::from("Config: one of `bind` or `peer` must be specified"));
+            return Err(ConfigError::Missing {
+                message: "one of `bind` or `peer` must be specified".to_string(),
+            });
         }
         let peer = if let Some(p) = c.peer {
-            Some(to_socket_addr(&p)?)
+            Some(to_socket_addr(&p).map_err(|e| e.at("peer", s))?)
         } else {
             None
         };
-        let bind = if let Some(b) = c.bind {
-            Some(to_socket_addr(&b)?)
+        let mut bind = Vec::new();
+        match c.bind {
+            None => {}
+            Some(yaml::Value::String(ref b)) => {
+                bind.extend(parse_bind(b).map_err(|e| e.at("bind", s))?)
+            }
+            Some(yaml::Value::Sequence(ref bs)) if !bs.is_empty() => {
+                for b in bs {
+                    match *b {
+                        yaml::Value::String(ref b) => {
+                            bind.extend(parse_bind(b).map_err(|e| e.at("bind", s))?)
+                        }
+                        _ => return Err(invalid("bind", "`bind` must be strings")),
+                    }
+                }
+            }
+            Some(_) => {
+                return Err(invalid("bind", "`bind` must be a string or a list of strings"))
+            }
+        }
+        if bind.len() > MAX_BIND {
+            return Err(invalid("bind",
+                               &format!("too many bind addresses, at most {} are allowed",
+                                        MAX_BIND)));
+        }
+
+        let mut priority_ports = Vec::new();
+        for p in c.priority_ports.unwrap_or_default() {
+            priority_ports.push(parse_port_range(&p).map_err(|e| e.at("priority_ports", s))?);
+        }
+        if c.queue_limit == Some(0) {
+            return Err(invalid("queue_limit", "`queue_limit` must be positive"));
+        }
+
+        let address = if let Some(a) = c.address {
+            Some(parse_cidr(&a).map_err(|e| e.at("address", s))?)
+        } else {
+            None
+        };
+        let peer_address = if let Some(a) = c.peer_address {
+            Some(a.parse::<IpAddr>()
+                .map_err(|e| ValueError::Invalid(format!("`{}`: {}", a, e)).at("peer_address", s))?)
+        } else {
+            None
+        };
+        match (address, peer_address) {
+            (None, Some(_)) => {
+                return Err(invalid("peer_address", "`peer_address` requires `address`"));
+            }
+            (Some((a, _)), Some(p)) if a.is_ipv4() != p.is_ipv4() => {
+                return Err(invalid("peer_address",
+                                   "`address` and `peer_address` must be of the same address \
+                                    family"));
+            }
+            _ => {}
+        }
+        let mut routes = Vec::new();
+        for r in c.routes.unwrap_or_default() {
+            routes.push(parse_route(&r).map_err(|e| e.at("routes", s))?);
+        }
+        if !routes.is_empty() && c.up != Some(true) {
+            return Err(invalid("routes", "`routes` requires `up: true`"));
+        }
+
+        let mss_clamp = match c.mss_clamp {
+            None => None,
+            Some(yaml::Value::String(ref s)) if s == "auto" => Some(MssClamp::Auto),
+            Some(yaml::Value::I64(n)) if n > 0 && n <= 0xffff => Some(MssClamp::Fixed(n as u16)),
+            Some(_) => {
+                return Err(invalid("mss_clamp", "`mss_clamp` must be `auto` or a number"));
+            }
+        };
+
+        let fec = match (c.fec_group, c.fec_parity) {
+            (None, None) => None,
+            (group, parity) => {
+                let group = group.unwrap_or(10);
+                let parity = parity.unwrap_or(1);
+                if group == 0 || parity == 0 || group as u32 + parity as u32 > 255 {
+                    return Err(invalid(if c.fec_group.is_some() {
+                                           "fec_group"
+                                       } else {
+                                           "fec_parity"
+                                       },
+                                       "`fec_group` and `fec_parity` must be positive, and add \
+                                        up to at most 255"));
+                }
+                Some((group, parity))
+            }
+        };
+
+        let padding = match c.padding {
+            None => None,
+            Some(yaml::Value::String(ref s)) if s == "none" => None,
+            Some(yaml::Value::String(ref s)) if s == "mtu" => Some(Padding::Mtu),
+            Some(yaml::Value::String(ref s)) if s == "random" => Some(Padding::Random),
+            Some(yaml::Value::Sequence(ref v)) if !v.is_empty() => {
+                let mut buckets = Vec::new();
+                for b in v {
+                    match *b {
+                        yaml::Value::I64(n) if n > 0 && n <= 0xffff => buckets.push(n as u16),
+                        _ => {
+                            return Err(invalid("padding",
+                                               "`padding` buckets must be numbers between 1 \
+                                                and 65535"));
+                        }
+                    }
+                }
+                buckets.sort();
+                Some(Padding::Buckets(buckets))
+            }
+            Some(_) => {
+                return Err(invalid("padding",
+                                   "`padding` must be `none`, `mtu`, `random` or a list of \
+                                    sizes"));
+            }
+        };
+
+        match (c.cover_rate, c.cover_size) {
+            (Some(0), _) => return Err(invalid("cover_rate", "`cover_rate` must be positive")),
+            (None, Some(_)) => {
+                return Err(invalid("cover_size", "`cover_size` requires `cover_rate`"))
+            }
+            (Some(_), _) if c.pmtud == Some(true) => {
+                return Err(invalid("pmtud", "`pmtud` cannot be used with `cover_rate`"));
+            }
+            // The outer TOS of real packets would differ from that of dummy ones.
+            (Some(_), _) if c.copy_dscp == Some(true) || c.ecn == Some(true) => {
+                return Err(invalid(if c.copy_dscp == Some(true) { "copy_dscp" } else { "ecn" },
+                                   "`copy_dscp` and `ecn` cannot be used with `cover_rate`"));
+            }
+            _ => {}
+        }
+
+        if (c.rotate_port.is_some() || c.rotate_port_idle.is_some()) &&
+           (peer.is_none() || bind.len() > 1) {
+            return Err(invalid(if c.rotate_port.is_some() {
+                                   "rotate_port"
+                               } else {
+                                   "rotate_port_idle"
+                               },
+                               "`rotate_port` and `rotate_port_idle` require `peer`, and at \
+                                most one `bind` address"));
+        }
+        if c.rotate_port == Some(0) {
+            return Err(invalid("rotate_port", "`rotate_port` must be positive"));
+        }
+        if c.rotate_port_idle == Some(0) {
+            return Err(invalid("rotate_port_idle", "`rotate_port_idle` must be positive"));
+        }
+
+        if c.keepalive == Some(0) {
+            return Err(invalid("keepalive", "`keepalive` must be positive"));
+        }
+
+        if let Some(ref n) = c.dev_name {
+            // IFNAMSIZ is 16.
+            if n.is_empty() || n.len() > 15 || n.contains('/') || n.contains(char::is_whitespace) ||
+               n.contains('\0') {
+                return Err(invalid("dev_name",
+                                   "`dev_name` must be 1 to 15 characters, without `/` or \
+                                    spaces"));
+            }
+        }
+        match c.mtu {
+            Some(m) if m < MIN_PACKET_MTU || m > 0xffff => {
+                return Err(invalid("mtu",
+                                   &format!("`mtu` must be between {} and 65535",
+                                            MIN_PACKET_MTU)));
+            }
+            _ => {}
+        }
+
+        let framing = match c.framing.as_ref().map(|f| f.as_str()) {
+            None | Some("none") => None,
+            Some("dtls") => Some(Framing::Dtls),
+            Some("quic") => Some(Framing::Quic),
+            Some("rtp") => Some(Framing::Rtp),
+            Some(_) => {
+                return Err(invalid("framing",
+                                   "`framing` must be `none`, `dtls`, `quic` or `rtp`"))
+            }
+        };
+
+        let metrics_listen = if let Some(a) = c.metrics_listen {
+            Some(to_socket_addr(&a).map_err(|e| e.at("metrics_listen", s))?)
         } else {
             None
         };
 
-        Ok(Config {
+        let config = Config {
             bind: bind,
             peer: peer,
             key: key,
@@ -95,19 +954,226 @@ impl Config {
             bufsize: c.bufsize.unwrap_or(65536),
             max_diff: c.max_diff.unwrap_or(DEFAULT_MAX_DIFF),
             dev_name: c.dev_name,
+            scheduler: c.scheduler.unwrap_or(false),
+            priority_ports: priority_ports,
+            queue_limit: c.queue_limit.unwrap_or(1000),
+            copy_dscp: c.copy_dscp.unwrap_or(false),
+            ecn: c.ecn.unwrap_or(false),
+            address: address,
+            peer_address: peer_address,
+            mtu: c.mtu,
+            outer_mtu: c.outer_mtu.unwrap_or(1500),
+            routes: routes,
+            up: c.up.unwrap_or(false),
+            pmtud: c.pmtud.unwrap_or(false),
+            on_pmtu_change: c.on_pmtu_change,
+            mss_clamp: mss_clamp,
+            fragment: c.fragment.unwrap_or(false),
+            fec: fec,
+            compress: c.compress.unwrap_or(false),
+            padding: padding,
+            cover_rate: c.cover_rate,
+            cover_size: c.cover_size,
+            framing: framing,
+            rotate_port: c.rotate_port,
+            rotate_port_idle: c.rotate_port_idle,
+            metrics_listen: metrics_listen,
+            keepalive: c.keepalive,
+        };
+
+        if config.cover_rate.is_some() && config.cover_mtu() < Some(MIN_PACKET_MTU) {
+            return Err(invalid(if config.cover_size.is_some() {
+                                   "cover_size"
+                               } else {
+                                   "outer_mtu"
+                               },
+                               "too small for cover traffic with the overhead of the options \
+                                used"));
+        }
+        if (config.mtu.is_none() || config.fragment) &&
+           tun_mtu(config.outer_mtu, config.ipv6())
+            .saturating_sub(config.framing_overhead() + config.message_overhead()) <
+           MIN_PACKET_MTU {
+            return Err(invalid("outer_mtu",
+                               "`outer_mtu` is too small for the overhead of the options used"));
+        }
+        Ok(config)
+    }
+
+    /// Whether the tunnel runs over IPv6: the address family of `peer`, or
+    /// of the first `bind` address.
+    pub fn ipv6(&self) -> bool {
+        self.peer.or_else(|| self.bind.first().cloned()).map_or(false, |a| a.is_ipv6())
+    }
+
+    /// Padding of packets. With cover traffic, every packet is padded to the
+    /// same size.
+    pub fn packet_padding(&self) -> Option<Padding> {
+        if self.cover_rate.is_some() {
+            Some(Padding::Mtu)
+        } else {
+            self.padding.clone()
+        }
+    }
+
+    /// Extra overhead of FEC parity messages and padding.
+    pub fn message_overhead(&self) -> u32 {
+        let mut overhead = 0;
+        if self.fec.is_some() {
+            overhead += fec::OVERHEAD;
+        }
+        if self.packet_padding().is_some() {
+            overhead += message::PADDING_HEADER_LEN as u32;
+        }
+        overhead
+    }
+
+    /// Bytes added to each packet by `framing`.
+    pub fn framing_overhead(&self) -> u32 {
+        Framer::new(self.framing).overhead() as u32
+    }
+
+    /// Largest packet that fits in a cover traffic packet, if `cover_rate` is
+    /// set.
+    pub fn cover_mtu(&self) -> Option<u32> {
+        self.cover_rate.map(|_| {
+            let size = self.cover_size.unwrap_or_else(|| {
+                // Largest UDP payload.
+                self.outer_mtu.saturating_sub(mtu::overhead(self.ipv6())) + OVERHEAD as u32
+            });
+            size.saturating_sub(OVERHEAD as u32 + self.framing_overhead() +
+                                self.message_overhead())
         })
     }
+
+    /// Largest packet that fits in `outer_mtu` and, with cover traffic, in
+    /// `cover_size`.
+    fn path_mtu(&self) -> u32 {
+        let m = tun_mtu(self.outer_mtu, self.ipv6())
+            .saturating_sub(self.framing_overhead() + self.message_overhead());
+        self.cover_mtu().map_or(m, |c| ::std::cmp::min(m, c))
+    }
+
+    /// MTU of the tun device: `mtu`, or the largest packet that fits in
+    /// `outer_mtu` and, with cover traffic, in `cover_size`.
+    pub fn tun_mtu(&self) -> u32 {
+        self.mtu.unwrap_or_else(|| self.path_mtu())
+    }
+
+    /// Largest packet that is sent in one piece. With `fragment`, larger
+    /// packets are split to fit in `outer_mtu`, whatever the MTU of the tun
+    /// device.
+    pub fn effective_mtu(&self) -> u32 {
+        if self.fragment {
+            self.path_mtu()
+        } else {
+            self.cover_mtu().unwrap_or_else(|| self.tun_mtu())
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let mut f = File::open(path)?;
+        let mut s = String::new();
+        f.read_to_string(&mut s)?;
+        Ok(Config::parse(&s)?)
+    }
+
+    /// Names of the options that differ in `new` and cannot be changed
+    /// without a restart. `key`, `max_diff`, `keepalive` and the scripts can
+    /// always be changed, `peer` unless it is added or removed.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        macro_rules! check {
+            ($($f:ident),*) => {
+                $(if self.$f != new.$f {
+                    changed.push(stringify!($f));
+                })*
+            }
+        }
+        check!(bind, bufsize, dev_name, scheduler, priority_ports, queue_limit, copy_dscp, ecn,
+               address, peer_address, mtu, outer_mtu, routes, up, pmtud, mss_clamp, fragment, fec,
+               compress, padding, cover_rate, cover_size, framing, rotate_port, rotate_port_idle,
+               metrics_listen);
+        if self.peer.is_some() != new.peer.is_some() {
+            changed.push("peer");
+        }
+        changed
+    }
+}
+
+/// Parse `"192.168.9.1/24"` or `"fd00::1"`. The prefix length defaults to
+/// 32 or 128.
+fn parse_cidr(s: &str) -> ::std::result::Result<(IpAddr, u8), ValueError> {
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next()
+        .unwrap()
+        .trim()
+        .parse::<IpAddr>()
+        .map_err(|e| ValueError::Invalid(format!("`{}`: {}", s, e)))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next().map(|p| p.trim().parse::<u8>()) {
+        None => max,
+        Some(Ok(p)) if p <= max => p,
+        _ => return Err(ValueError::Invalid(format!("invalid prefix length in `{}`", s))),
+    };
+    Ok((addr, prefix))
+}
+
+/// Parse a route destination, which must not have host bits set, e.g.
+/// `"10.0.0.0/8"` but not `"10.1.2.3/8"`.
+fn parse_route(s: &str) -> ::std::result::Result<(IpAddr, u8), ValueError> {
+    let (addr, prefix) = parse_cidr(s)?;
+    let octets = match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    };
+    let host_bits = octets.iter().enumerate().any(|(i, &b)| {
+        let network_bits = ::std::cmp::min((prefix as usize).saturating_sub(i * 8), 8);
+        network_bits < 8 && b & (0xff >> network_bits) != 0
+    });
+    if host_bits {
+        return Err(ValueError::Invalid(format!("`{}` has host bits set", s)));
+    }
+    Ok((addr, prefix))
 }
 
 pub fn decode_key(k: &str) -> Option<Key> {
     base64::decode(k.as_bytes()).ok().and_then(|k| Key::from_slice(k.as_slice()))
 }
 
-pub fn genkey_base64() -> String {
-    let k = gen_key();
+pub fn encode_key(k: &Key) -> String {
     base64::encode(k.0.as_ref())
 }
 
+pub fn genkey_base64() -> String {
+    encode_key(&gen_key())
+}
+
+/// Decode a private key, either bare or as `private_key: "..."`, as printed
+/// by `titun genkey --private`.
+pub fn decode_private_key(k: &str) -> Option<Scalar> {
+    let k = k.trim();
+    let k = if k.starts_with("private_key:") {
+        k["private_key:".len()..].trim().trim_matches('"')
+    } else {
+        k
+    };
+    base64::decode(k.as_bytes()).ok().and_then(|k| Scalar::from_slice(k.as_slice()))
+}
+
+pub fn decode_public_key(k: &str) -> Option<GroupElement> {
+    base64::decode(k.as_bytes()).ok().and_then(|k| GroupElement::from_slice(k.as_slice()))
+}
+
+pub fn gen_private_key_base64() -> String {
+    base64::encode(gen_private_key().0.as_ref())
+}
+
+/// Public key of a base64 encoded private key, in base64.
+pub fn public_key_base64(private: &str) -> Option<String> {
+    decode_private_key(private).map(|k| base64::encode(public_key(&k).0.as_ref()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,7 +1188,7 @@ mod tests {
     #[test]
     fn parse_config() {
         let c0 = Config {
-            bind: None,
+            bind: vec![],
             peer: Some("127.0.0.1:3000".parse().unwrap()),
             key: decode_key("Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=").unwrap(),
             on_up: None,
@@ -130,6 +1196,31 @@ mod tests {
             bufsize: 65536,
             max_diff: ::crypto::DEFAULT_MAX_DIFF,
             dev_name: None,
+            scheduler: false,
+            priority_ports: vec![],
+            queue_limit: 1000,
+            copy_dscp: false,
+            ecn: false,
+            address: None,
+            peer_address: None,
+            mtu: None,
+            outer_mtu: 1500,
+            routes: vec![],
+            up: false,
+            pmtud: false,
+            on_pmtu_change: None,
+            mss_clamp: None,
+            fragment: false,
+            fec: None,
+            compress: false,
+            padding: None,
+            cover_rate: None,
+            cover_size: None,
+            framing: None,
+            rotate_port: None,
+            rotate_port_idle: None,
+            metrics_listen: None,
+            keepalive: None,
         };
         let c = Config::parse(r#"---
 peer: "127.0.0.1:3000"
@@ -137,4 +1228,434 @@ key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
 "#);
         assert_eq!(c.unwrap(), c0);
     }
+
+    #[test]
+    fn parse_priority_ports() {
+        let c = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+scheduler: true
+priority_ports: ["22", "5060-5061"]
+"#)
+            .unwrap();
+        assert!(c.scheduler);
+        assert_eq!(c.priority_ports, vec![(22, 22), (5060, 5061)]);
+
+        assert!(parse_port_range("5061-5060").is_err());
+        assert!(parse_port_range("ssh").is_err());
+
+        assert!(Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+scheduler: true
+queue_limit: 0
+"#)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_fec() {
+        let c = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+fec_parity: 2
+"#)
+            .unwrap();
+        assert_eq!(c.fec, Some((10, 2)));
+        assert_eq!(c.padding, None);
+
+        assert!(Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+fec_group: 200
+fec_parity: 100
+"#)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_multiple_bind() {
+        let c = Config::parse(r#"---
+bind: ["127.0.0.1:5000-5002", "[::1]:443"]
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+"#)
+            .unwrap();
+        assert_eq!(c.bind,
+                   vec!["127.0.0.1:5000".parse().unwrap(),
+                        "127.0.0.1:5001".parse().unwrap(),
+                        "127.0.0.1:5002".parse().unwrap(),
+                        "[::1]:443".parse().unwrap()]);
+
+        let c = Config::parse(r#"---
+bind: "127.0.0.1:5000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+"#)
+            .unwrap();
+        assert_eq!(c.bind, vec!["127.0.0.1:5000".parse().unwrap()]);
+
+        assert!(parse_bind("127.0.0.1").is_err());
+        assert!(parse_bind("127.0.0.1:0-65535").is_ok());
+        assert!(Config::parse(r#"---
+bind: "127.0.0.1:1-2000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+"#)
+            .is_err());
+    }
+
+    #[test]
+    fn parse_cover_traffic() {
+        let c = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+cover_rate: 100
+cover_size: 1200
+"#)
+            .unwrap();
+        assert_eq!(c.cover_rate, Some(100));
+        assert_eq!(c.cover_size, Some(1200));
+
+        assert!(Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+cover_rate: 100
+pmtud: true
+"#)
+            .is_err());
+        let e = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+cover_rate: 100
+ecn: true
+"#)
+            .unwrap_err();
+        assert_eq!(e.key(), Some("ecn"));
+    }
+
+    #[test]
+    fn mtu_and_overhead() {
+        let parse = |extra: &str| {
+            Config::parse(&format!("peer: \"127.0.0.1:3000\"\n\
+                                    key: \"Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=\"\n{}",
+                                   extra))
+        };
+        assert_eq!(parse("").unwrap().tun_mtu(), 1432);
+        assert_eq!(parse("framing: dtls\nfec_group: 10").unwrap().tun_mtu(), 1432 - 13 - 9);
+        assert_eq!(parse("cover_rate: 10\ncover_size: 1040").unwrap().tun_mtu(), 997);
+        assert_eq!(parse("mtu: 1400\nouter_mtu: 80").unwrap().tun_mtu(), 1400);
+        assert_eq!(parse("mtu: 1400").unwrap().effective_mtu(), 1400);
+
+        // Packets larger than fit in `outer_mtu` are fragmented.
+        let c = parse("mtu: 1500\nouter_mtu: 1280\nfragment: true").unwrap();
+        assert_eq!(c.tun_mtu(), 1500);
+        assert_eq!(c.effective_mtu(), 1280 - 68);
+        assert!(parse("mtu: 1500\nouter_mtu: 80\nfragment: true").is_err());
+
+        let e = parse("outer_mtu: 80\nframing: dtls").unwrap_err();
+        assert_eq!(e.key(), Some("outer_mtu"));
+        let e = parse("cover_rate: 10\ncover_size: 100").unwrap_err();
+        assert_eq!(e.key(), Some("cover_size"));
+    }
+
+    #[test]
+    fn parse_interface_config() {
+        let c = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+address: "192.168.9.2"
+peer_address: "192.168.9.1"
+mtu: 1400
+mss_clamp: auto
+padding: [1024, 256]
+framing: dtls
+routes: ["10.0.0.0/8", "fd00::/8"]
+up: true
+"#)
+            .unwrap();
+        assert_eq!(c.address, Some(("192.168.9.2".parse().unwrap(), 32)));
+        assert_eq!(c.peer_address, Some("192.168.9.1".parse().unwrap()));
+        assert_eq!(c.routes,
+                   vec![("10.0.0.0".parse().unwrap(), 8), ("fd00::".parse().unwrap(), 8)]);
+        assert!(c.up);
+        assert_eq!(c.mss_clamp, Some(MssClamp::Auto));
+        assert_eq!(c.padding, Some(Padding::Buckets(vec![256, 1024])));
+        assert_eq!(c.framing, Some(Framing::Dtls));
+        assert_eq!(c.fec, None);
+
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0/8").is_err());
+        assert!(parse_route("10.1.2.3/8").is_err());
+        assert!(parse_route("10.1.2.3/32").is_ok());
+        assert!(parse_route("fd00::1/64").is_err());
+        assert!(parse_route("fd00:0:0:1::/64").is_ok());
+        assert!(parse_route("0.0.0.0/0").is_ok());
+
+        assert!(Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+routes: ["10.0.0.0/8"]
+"#)
+            .is_err());
+    }
+
+    #[test]
+    fn config_errors() {
+        let e = Config::parse("peer: \"127.0.0.1:3000\"\nkey: [a\n").unwrap_err();
+        match e {
+            ConfigError::Syntax { location: Some(l), .. } => assert_eq!(l.line, 3),
+            e => panic!("{:?}", e),
+        }
+
+        let e = Config::parse_strict(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+mtuu: 1400
+"#)
+            .unwrap_err();
+        assert_eq!(e,
+                   ConfigError::Unknown {
+                       key: "mtuu".to_string(),
+                       location: Some(Location {
+                           line: 4,
+                           column: 1,
+                       }),
+                   });
+        assert_eq!(e.to_string(), "Config: line 4 column 1: unknown option `mtuu`");
+        assert!(Config::parse(r#"---
+strict: true
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+mtuu: 1400
+"#)
+            .is_err());
+
+        let e = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+mtu: large
+"#)
+            .unwrap_err();
+        assert_eq!(e.key(), Some("mtu"));
+        assert_eq!(e.location(),
+                   Some(Location {
+                       line: 4,
+                       column: 6,
+                   }));
+
+        // Flow style and nested values.
+        let src = "{peer: \"127.0.0.1:3000\", routes: [\"10.0.0.0/8\"],\n \
+                   key: \"Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=\", mtuu: 1}";
+        assert_eq!(locate(src, "mtuu"),
+                   Some(Location {
+                       line: 2,
+                       column: 55,
+                   }));
+        assert_eq!(locate_value(src, "routes"),
+                   Some(Location {
+                       line: 1,
+                       column: 34,
+                   }));
+        assert_eq!(locate(src, "10.0.0.0/8"), None);
+
+        let e = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoDw=="
+"#)
+            .unwrap_err();
+        assert_eq!(e.key(), Some("key"));
+        assert!(e.to_string().contains("must be 32 bytes"));
+
+        let e = Config::parse(r#"---
+peer: "host.invalid:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+"#)
+            .unwrap_err();
+        match e {
+            ConfigError::Resolve { ref key, ref host, .. } => {
+                assert_eq!(key, "peer");
+                assert_eq!(host, "host.invalid:3000");
+            }
+            e => panic!("{:?}", e),
+        }
+
+        match Config::parse("key: \"Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=\"\n") {
+            Err(ConfigError::Missing { .. }) => {}
+            r => panic!("{:?}", r.err()),
+        }
+        match Config::parse("peer: \"127.0.0.1:3000\"\n") {
+            Err(ConfigError::Missing { .. }) => {}
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn key_sources() {
+        use std::fs::{self, OpenOptions};
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let k = "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=";
+        let key = decode_key(k).unwrap();
+        let parse = |extra: &str| Config::parse(&format!("peer: \"127.0.0.1:3000\"\n{}", extra));
+
+        let dir = env::temp_dir().join(format!("titun-key-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key");
+        let _ = fs::remove_file(&path);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .open(&path)
+            .unwrap()
+            .write_all(format!("{}\n", k).as_bytes())
+            .unwrap();
+        assert_eq!(parse(&format!("key_file: \"{}\"", path.display())).unwrap().key, key);
+
+        env::set_var("CREDENTIALS_DIRECTORY", &dir);
+        assert_eq!(parse("key_credential: key").unwrap().key, key);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let e = parse(&format!("key_file: \"{}\"", path.display())).unwrap_err();
+        assert_eq!(e.key(), Some("key_file"));
+        assert!(e.to_string().contains("readable by everyone"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        env::set_var("TITUN_TEST_KEY", k);
+        assert_eq!(parse("key_env: TITUN_TEST_KEY").unwrap().key, key);
+        assert!(parse("key_env: TITUN_TEST_NO_SUCH_KEY").is_err());
+
+        assert_eq!(parse(&format!("key_command: \"echo {}\"", k)).unwrap().key, key);
+        assert!(parse("key_command: \"false\"").is_err());
+
+        let e = parse(&format!("key: \"{}\"\nkey_env: TITUN_TEST_KEY", k)).unwrap_err();
+        assert_eq!(e.key(), Some("key_env"));
+        match parse("") {
+            Err(ConfigError::Missing { .. }) => {}
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn passphrase_keys() {
+        let parse = |extra: &str| {
+            Config::parse(&format!("peer: \"127.0.0.1:3000\"\npassphrase_ops: 1\n\
+                                    passphrase_mem: 1\n{}",
+                                   extra))
+        };
+
+        let k1 = parse("passphrase: \"correct horse\"").unwrap().key;
+        assert_eq!(parse("passphrase: \"correct horse\"").unwrap().key, k1);
+        assert!(parse("passphrase: \"battery staple\"").unwrap().key != k1);
+        assert!(parse("passphrase: \"correct horse\"\npassphrase_salt: other").unwrap().key != k1);
+
+        let e = parse("passphrase: \"correct horse\"\nkey_env: TITUN_TEST_KEY").unwrap_err();
+        assert_eq!(e.key(), Some("passphrase"));
+        let e = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+passphrase: "correct horse"
+passphrase_mem: 0
+"#)
+            .unwrap_err();
+        assert_eq!(e.key(), Some("passphrase_mem"));
+        let e = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+passphrase_salt: other
+"#)
+            .unwrap_err();
+        assert_eq!(e.key(), Some("passphrase_salt"));
+    }
+
+    #[test]
+    fn x25519_keys() {
+        let a = gen_private_key_base64();
+        let b = gen_private_key_base64();
+        let config = |private: &str, public: &str, extra: &str| {
+            Config::parse(&format!("peer: \"127.0.0.1:3000\"\nprivate_key: \"{}\"\n\
+                                    public_key: \"{}\"\n{}",
+                                   private,
+                                   public,
+                                   extra))
+        };
+
+        let ka = config(&a, &public_key_base64(&b).unwrap(), "").unwrap().key;
+        let kb = config(&b, &public_key_base64(&a).unwrap(), "").unwrap().key;
+        assert_eq!(ka, kb);
+
+        let psk = "key: \"Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8=\"";
+        let pa = config(&a, &public_key_base64(&b).unwrap(), psk).unwrap().key;
+        let pb = config(&b, &public_key_base64(&a).unwrap(), psk).unwrap().key;
+        assert_eq!(pa, pb);
+        assert!(pa != ka);
+
+        // `private_key_file` takes the output of `genkey --private` as is.
+        {
+            use std::fs::{self, OpenOptions};
+            use std::io::Write;
+            use std::os::unix::fs::OpenOptionsExt;
+
+            let path = env::temp_dir().join(format!("titun-private-test-{}",
+                                                    ::std::process::id()));
+            let _ = fs::remove_file(&path);
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .mode(0o600)
+                .open(&path)
+                .unwrap()
+                .write_all(format!("private_key: \"{}\"\n", a).as_bytes())
+                .unwrap();
+            let c = Config::parse(&format!("peer: \"127.0.0.1:3000\"\nprivate_key_file: \"{}\"\n\
+                                            public_key: \"{}\"",
+                                           path.display(),
+                                           public_key_base64(&b).unwrap()))
+                .unwrap();
+            assert_eq!(c.key, ka);
+            fs::remove_file(&path).unwrap();
+        }
+        assert_eq!(public_key_base64(&format!("private_key: \"{}\"\n", a)),
+                   public_key_base64(&a));
+
+        let e = config(&a, "AAAA", "").unwrap_err();
+        assert_eq!(e.key(), Some("public_key"));
+        let e = config("AAAA", &public_key_base64(&b).unwrap(), "").unwrap_err();
+        assert_eq!(e.key(), Some("private_key"));
+        let e = Config::parse(&format!("peer: \"127.0.0.1:3000\"\nprivate_key: \"{}\"", a))
+            .unwrap_err();
+        assert!(e.to_string().contains("public_key"));
+        let e = Config::parse(&format!("peer: \"127.0.0.1:3000\"\n{}\npublic_key: \"{}\"",
+                                       psk,
+                                       public_key_base64(&a).unwrap()))
+            .unwrap_err();
+        assert_eq!(e.key(), Some("public_key"));
+    }
+
+    #[test]
+    fn restart_required() {
+        let c = Config::parse(r#"---
+peer: "127.0.0.1:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+mtu: 1400
+"#)
+            .unwrap();
+        let c1 = Config::parse(r#"---
+peer: "127.0.0.2:3000"
+key: "T7DEdB4b0nK6F6hE0/+8SzepNiJ+sFz1AXMYagvUIXc="
+max_diff: 1000
+keepalive: 25
+on_down: "true"
+mtu: 1400
+"#)
+            .unwrap();
+        assert_eq!(c1.keepalive, Some(25));
+        assert!(c.restart_required(&c1).is_empty());
+
+        let c2 = Config::parse(r#"---
+bind: "0.0.0.0:3000"
+key: "Q3bSSKKonSsSt09ShImoD6JXf4z+r2ngQaCk/FFKwF8="
+mtu: 1300
+"#)
+            .unwrap();
+        assert_eq!(c.restart_required(&c2), vec!["bind", "mtu", "peer"]);
+    }
 }
@@ -0,0 +1,240 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// A UNIX domain socket per tunnel, answering every connection with the
+// status of the tunnel as JSON.
+
+use error::Result;
+use futures::{Async, Future, Poll};
+use mio::{self, Evented, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use serde_json;
+use stats::Stats;
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::{Handle, PollEvented};
+
+pub const CONTROL_DIR: &'static str = "/run/titun";
+
+pub fn socket_path(tunnel: &str) -> PathBuf {
+    Path::new(CONTROL_DIR).join(format!("{}.sock", tunnel))
+}
+
+/// Status of a running tunnel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    /// Name of the tun device.
+    pub tun: String,
+    /// Local addresses of the UDP sockets.
+    pub bind: Vec<String>,
+    /// Current peer endpoint.
+    pub peer: Option<String>,
+    /// Unix time of the last authenticated packet received.
+    pub last_rx: Option<u64>,
+    /// Unix time of the last packet sent.
+    pub last_tx: Option<u64>,
+    pub stats: Stats,
+}
+
+pub fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn ago(t: Option<u64>) -> String {
+    match t {
+        None => "never".to_string(),
+        Some(t) => {
+            let now = unix_time(SystemTime::now());
+            format!("{} seconds ago", now.saturating_sub(t))
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "tunnel: {}", self.tun)?;
+        writeln!(f, "  bind: {}", self.bind.join(", "))?;
+        writeln!(f,
+                 "  peer: {}",
+                 self.peer.as_ref().map(|p| p.as_str()).unwrap_or("(none)"))?;
+        writeln!(f, "  last received: {}", ago(self.last_rx))?;
+        writeln!(f, "  last sent: {}", ago(self.last_tx))?;
+        write!(f, "{}", self.stats)
+    }
+}
+
+/// Query the status of a running tunnel.
+pub fn query(path: &Path) -> Result<Status> {
+    let mut s = UnixStream::connect(path)?;
+    let mut out = String::new();
+    s.read_to_string(&mut out)?;
+    Ok(serde_json::from_str(&out)?)
+}
+
+/// Names of running tunnels, i.e. sockets in `CONTROL_DIR`.
+pub fn list() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let dir = match fs::read_dir(CONTROL_DIR) {
+        Ok(d) => d,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(From::from(e)),
+    };
+    for e in dir {
+        let p = e?.path();
+        if p.extension().map_or(false, |e| e == "sock") {
+            if let Some(n) = p.file_stem().and_then(|n| n.to_str()) {
+                names.push(n.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+struct Listener(UnixListener);
+
+impl Evented for Listener {
+    fn register(&self,
+                poll: &mio::Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &mio::Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+/// Serves the control socket. The socket file is removed when this is
+/// dropped.
+pub struct ControlServer<F> {
+    listener: PollEvented<Listener>,
+    path: PathBuf,
+    status: F,
+}
+
+impl<F> ControlServer<F>
+    where F: Fn() -> Status
+{
+    pub fn new(path: PathBuf, status: F, handle: &Handle) -> Result<ControlServer<F>> {
+        if let Some(dir) = path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        // Remove a stale socket.
+        match fs::remove_file(&path) {
+            Err(ref e) if e.kind() != ErrorKind::NotFound => warn!("{}: {}", path.display(), e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(ControlServer {
+            listener: PollEvented::new(Listener(listener), handle)?,
+            path: path,
+            status: status,
+        })
+    }
+}
+
+impl<F> Future for ControlServer<F>
+    where F: Fn() -> Status
+{
+    type Item = ();
+    type Error = ::error::TiTunError;
+
+    fn poll(&mut self) -> Poll<(), ::error::TiTunError> {
+        loop {
+            if let Async::NotReady = self.listener.poll_read() {
+                return Ok(Async::NotReady);
+            }
+            let mut stream = match self.listener.get_ref().0.accept() {
+                Ok((s, _)) => s,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.listener.need_read();
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(From::from(e)),
+            };
+            // The status is small enough to fit in the socket buffer, so
+            // this does not block.
+            let status = serde_json::to_string(&(self.status)()).unwrap();
+            if let Err(e) = stream.write_all(status.as_bytes()) {
+                debug!("control socket: {}", e);
+            }
+        }
+    }
+}
+
+impl<F> Drop for ControlServer<F> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use std::env;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn control_socket() {
+        let status = Status {
+            tun: "tun7".to_string(),
+            bind: vec!["0.0.0.0:5000".to_string()],
+            peer: Some("192.0.2.1:5000".to_string()),
+            last_rx: Some(unix_time(SystemTime::now())),
+            last_tx: None,
+            stats: Stats::default(),
+        };
+        let path = env::temp_dir().join(format!("titun-test-{}/tun7.sock", ::std::process::id()));
+
+        let mut core = Core::new().unwrap();
+        let s = status.clone();
+        let server = ControlServer::new(path.clone(), move || s.clone(), &core.handle()).unwrap();
+        let p = path.clone();
+        let client = thread::spawn(move || query(&p).unwrap());
+        let timeout = ::tokio_core::reactor::Timeout::new(::std::time::Duration::from_millis(500),
+                                                          &core.handle())
+            .unwrap();
+        let _ = core.run(server.select(timeout.map_err(From::from)));
+        assert_eq!(client.join().unwrap(), status);
+        assert!(!path.exists());
+        let _ = fs::remove_dir(path.parent().unwrap());
+
+        assert!(format!("{}", status).contains("last sent: never"));
+    }
+}
@@ -16,12 +16,29 @@
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
 use byteorder::{BigEndian, ByteOrder};
-use sodiumoxide::crypto::secretbox::{Key, Nonce, open, seal};
+use nix::libc::{c_char, c_int, c_ulonglong, size_t};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519::{GroupElement, Scalar, scalarmult,
+                                                   scalarmult_base};
+use sodiumoxide::crypto::secretbox::{KEYBYTES, Key, Nonce, open, seal};
 use sodiumoxide::randombytes::randombytes_into;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const DEFAULT_MAX_DIFF: u64 = 1000;
 
+/// Bytes added by `encrypt`: 8 bytes timestamp, 16 bytes auth tag, 16 bytes
+/// random nonce.
+pub const OVERHEAD: usize = 40;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecryptError {
+    /// Too short, or failed authentication.
+    Invalid,
+    /// Authentic, but the timestamp is too far from now.
+    Timestamp,
+}
+
 pub struct Crypto {
     key: Key,
     max_diff: u64,
@@ -53,30 +70,112 @@ impl Crypto {
         e
     }
 
-    pub fn decrypt(&self, msg: &[u8]) -> Option<Vec<u8>> {
-        // 8 bytes timestamp, 16 bytes auth tag, 16 bytes random nonce.
-        if msg.len() < 40 {
-            None
+    pub fn decrypt(&self, msg: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if msg.len() < OVERHEAD {
+            Err(DecryptError::Invalid)
         } else {
             let (c, n) = msg.split_at(msg.len() - 16);
             let mut nonce = Nonce([0; 24]);
             nonce.0[8..].copy_from_slice(n);
-            open(c, &nonce, &self.key).ok().and_then(|mut m| {
+            open(c, &nonce, &self.key).map_err(|_| DecryptError::Invalid).and_then(|mut m| {
                 let len = m.len().checked_sub(8).unwrap();
                 let t = BigEndian::read_u64(&m[len..]);
                 let t0 = system_time_to_millis_epoch(SystemTime::now());
                 let diff = if t > t0 { t - t0 } else { t0 - t };
                 if diff <= self.max_diff {
                     m.truncate(len);
-                    Some(m)
+                    Ok(m)
                 } else {
-                    None
+                    Err(DecryptError::Timestamp)
                 }
             })
         }
     }
 }
 
+/// Default Argon2id parameters for passphrase derived keys: 3 passes over
+/// 64 MiB.
+pub const DEFAULT_PWHASH_OPS: u64 = 3;
+pub const DEFAULT_PWHASH_MEM: u64 = 64;
+
+/// Maximum memory for passphrase key derivation, in MiB.
+pub const MAX_PWHASH_MEM: u64 = 4096;
+
+/// Salt used if none is specified.
+pub const DEFAULT_PWHASH_SALT: &'static str = "titun";
+
+// Not wrapped by sodiumoxide. Argon2id needs libsodium 1.0.13.
+const CRYPTO_PWHASH_ALG_ARGON2ID13: c_int = 2;
+const CRYPTO_PWHASH_SALTBYTES: usize = 16;
+
+extern "C" {
+    fn crypto_pwhash(out: *mut u8,
+                     outlen: c_ulonglong,
+                     passwd: *const c_char,
+                     passwdlen: c_ulonglong,
+                     salt: *const u8,
+                     opslimit: c_ulonglong,
+                     memlimit: size_t,
+                     alg: c_int)
+                     -> c_int;
+}
+
+/// Derive a key from a passphrase with Argon2id. `ops` is the number of
+/// passes, `mem` is the memory to use in MiB. The salt can be any string,
+/// e.g. the name of the tunnel. Returns `None` if `ops` is 0, `mem` is not
+/// between 1 and `MAX_PWHASH_MEM`, or libsodium fails, e.g. because there is
+/// not enough memory.
+pub fn derive_key(passphrase: &[u8], salt: &str, ops: u64, mem: u64) -> Option<Key> {
+    if ops == 0 || mem == 0 || mem > MAX_PWHASH_MEM {
+        return None;
+    }
+    let salt = sha256::hash(salt.as_bytes());
+    let mut key = [0u8; KEYBYTES];
+    let r = unsafe {
+        crypto_pwhash(key.as_mut_ptr(),
+                      key.len() as c_ulonglong,
+                      passphrase.as_ptr() as *const c_char,
+                      passphrase.len() as c_ulonglong,
+                      salt.0[..CRYPTO_PWHASH_SALTBYTES].as_ptr(),
+                      ops as c_ulonglong,
+                      (mem << 20) as size_t,
+                      CRYPTO_PWHASH_ALG_ARGON2ID13)
+    };
+    if r == 0 { Some(Key(key)) } else { None }
+}
+
+/// Generate an X25519 private key.
+pub fn gen_private_key() -> Scalar {
+    let mut k = [0u8; 32];
+    randombytes_into(&mut k);
+    Scalar(k)
+}
+
+/// The X25519 public key of a private key.
+pub fn public_key(private: &Scalar) -> GroupElement {
+    scalarmult_base(private)
+}
+
+/// Derive the key shared with a peer from our X25519 private key and the
+/// peer's public key, optionally mixed with a pre-shared key. Both ends get
+/// the same key. Returns `None` if the public key is invalid.
+pub fn dh_key(private: &Scalar, peer: &GroupElement, psk: Option<&Key>) -> Option<Key> {
+    let shared = match scalarmult(private, peer) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    let ours = public_key(private);
+    let (a, b) = if ours.0 < peer.0 { (ours.0, peer.0) } else { (peer.0, ours.0) };
+
+    let mut m = Vec::with_capacity(12 + 32 * 3);
+    m.extend_from_slice(b"titun x25519");
+    m.extend_from_slice(&shared.0);
+    m.extend_from_slice(&a);
+    m.extend_from_slice(&b);
+    let psk = hmacsha256::Key(psk.map_or([0u8; 32], |k| k.0));
+    Some(Key(hmacsha256::authenticate(&m, &psk).0))
+}
+
 fn system_time_to_millis_epoch(t: SystemTime) -> u64 {
     let d = t.duration_since(UNIX_EPOCH).unwrap();
     (d * 1000).as_secs()
@@ -97,15 +196,43 @@ mod tests {
         let c = cr.encrypt(&[2, 0, 1, 7]);
         let p = cr.decrypt(c.as_slice());
 
-        assert_eq!(p, Some(vec![2, 0, 1, 7]));
-        assert_eq!(cr.decrypt(&[3, 4, 8, 1]), None);
+        assert_eq!(p, Ok(vec![2, 0, 1, 7]));
+        assert_eq!(cr.decrypt(&[3, 4, 8, 1]), Err(DecryptError::Invalid));
 
         sleep(Duration::from_secs(2));
-        assert!(cr.decrypt(c.as_slice()).is_none());
+        assert_eq!(cr.decrypt(c.as_slice()), Err(DecryptError::Timestamp));
 
         let c1 = cr.encrypt(&[]);
         let p = cr.decrypt(c1.as_slice());
 
-        assert_eq!(p, Some(vec![]));
+        assert_eq!(p, Ok(vec![]));
+    }
+
+    #[test]
+    fn key_derivation() {
+        let k = derive_key(b"correct horse battery staple", "tun0", 1, 1).unwrap();
+        assert_eq!(derive_key(b"correct horse battery staple", "tun0", 1, 1), Some(k.clone()));
+        assert!(derive_key(b"correct horse battery staple", "tun1", 1, 1) != Some(k.clone()));
+        assert!(derive_key(b"correct horse battery stapler", "tun0", 1, 1) != Some(k.clone()));
+        assert!(derive_key(b"correct horse battery staple", "tun0", 2, 1) != Some(k));
+        assert_eq!(derive_key(b"correct horse battery staple", "tun0", 0, 1), None);
+        assert_eq!(derive_key(b"correct horse battery staple", "tun0", 1, 1 << 44), None);
+    }
+
+    #[test]
+    fn dh() {
+        let a = gen_private_key();
+        let b = gen_private_key();
+        let c = gen_private_key();
+        let k = dh_key(&a, &public_key(&b), None).unwrap();
+        assert_eq!(dh_key(&b, &public_key(&a), None), Some(k.clone()));
+        assert!(dh_key(&a, &public_key(&c), None) != Some(k.clone()));
+
+        let psk = gen_key();
+        let k1 = dh_key(&a, &public_key(&b), Some(&psk)).unwrap();
+        assert!(k1 != k);
+        assert_eq!(dh_key(&b, &public_key(&a), Some(&psk)), Some(k1));
+
+        assert_eq!(dh_key(&a, &GroupElement([0; 32]), None), None);
     }
 }
@@ -15,6 +15,7 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
+use config::ConfigError;
 use std::convert::From;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
@@ -23,14 +24,90 @@ use std::io;
 #[derive(Debug)]
 pub enum TiTunError {
     IoErr(io::Error),
+    ConfigErr(ConfigError),
+    /// Creating or configuring the tun device failed.
+    DeviceErr(String, io::Error),
+    /// Binding or using UDP sockets failed.
+    NetworkErr(String, io::Error),
+    CryptoErr(String),
+    JsonErr(::serde_json::Error),
+    LoggerErr(::log::SetLoggerError),
     OtherErr(String),
     GracefulExit,
 }
 
+/// Category of an error, which decides the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Config,
+    Permission,
+    Device,
+    Network,
+    Crypto,
+    Other,
+}
+
+impl ErrorKind {
+    /// Exit code for errors of this kind, from sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            // EX_CONFIG.
+            ErrorKind::Config => 78,
+            // EX_NOPERM.
+            ErrorKind::Permission => 77,
+            // EX_OSERR.
+            ErrorKind::Device => 71,
+            // EX_UNAVAILABLE.
+            ErrorKind::Network => 69,
+            // EX_DATAERR.
+            ErrorKind::Crypto => 65,
+            ErrorKind::Other => 1,
+        }
+    }
+}
+
+impl TiTunError {
+    pub fn device<S: Into<String>>(what: S, e: io::Error) -> TiTunError {
+        TiTunError::DeviceErr(what.into(), e)
+    }
+
+    pub fn network<S: Into<String>>(what: S, e: io::Error) -> TiTunError {
+        TiTunError::NetworkErr(what.into(), e)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            TiTunError::IoErr(ref e) |
+            TiTunError::DeviceErr(_, ref e) |
+            TiTunError::NetworkErr(_, ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                ErrorKind::Permission
+            }
+            TiTunError::ConfigErr(_) => ErrorKind::Config,
+            TiTunError::DeviceErr(..) => ErrorKind::Device,
+            TiTunError::NetworkErr(..) => ErrorKind::Network,
+            TiTunError::CryptoErr(_) => ErrorKind::Crypto,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            TiTunError::GracefulExit => 0,
+            _ => self.kind().exit_code(),
+        }
+    }
+}
+
 impl Display for TiTunError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
             TiTunError::IoErr(ref e) => write!(f, "IO Error: {}", e),
+            TiTunError::ConfigErr(ref e) => e.fmt(f),
+            TiTunError::DeviceErr(ref what, ref e) |
+            TiTunError::NetworkErr(ref what, ref e) => write!(f, "{}: {}", what, e),
+            TiTunError::CryptoErr(ref e) => write!(f, "Crypto: {}", e),
+            TiTunError::JsonErr(ref e) => write!(f, "JSON: {}", e),
+            TiTunError::LoggerErr(ref e) => write!(f, "Logger: {}", e),
             TiTunError::OtherErr(ref e) => e.fmt(f),
             TiTunError::GracefulExit => write!(f, "GracefulExit"),
         }
@@ -41,10 +118,28 @@ impl Error for TiTunError {
     fn description(&self) -> &str {
         match *self {
             TiTunError::IoErr(ref e) => e.description(),
-            TiTunError::OtherErr(ref e) => e.as_str(),
+            TiTunError::ConfigErr(ref e) => e.description(),
+            TiTunError::DeviceErr(ref what, _) |
+            TiTunError::NetworkErr(ref what, _) |
+            TiTunError::CryptoErr(ref what) |
+            TiTunError::OtherErr(ref what) => what.as_str(),
+            TiTunError::JsonErr(ref e) => e.description(),
+            TiTunError::LoggerErr(ref e) => e.description(),
             TiTunError::GracefulExit => "GracefulExit",
         }
     }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            TiTunError::IoErr(ref e) |
+            TiTunError::DeviceErr(_, ref e) |
+            TiTunError::NetworkErr(_, ref e) => Some(e),
+            TiTunError::ConfigErr(ref e) => Some(e),
+            TiTunError::JsonErr(ref e) => Some(e),
+            TiTunError::LoggerErr(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for TiTunError {
@@ -53,6 +148,24 @@ impl From<io::Error> for TiTunError {
     }
 }
 
+impl From<ConfigError> for TiTunError {
+    fn from(e: ConfigError) -> TiTunError {
+        TiTunError::ConfigErr(e)
+    }
+}
+
+impl From<::serde_json::Error> for TiTunError {
+    fn from(e: ::serde_json::Error) -> TiTunError {
+        TiTunError::JsonErr(e)
+    }
+}
+
+impl From<::log::SetLoggerError> for TiTunError {
+    fn from(e: ::log::SetLoggerError) -> TiTunError {
+        TiTunError::LoggerErr(e)
+    }
+}
+
 impl<'a> From<&'a str> for TiTunError {
     fn from(e: &'a str) -> TiTunError {
         TiTunError::OtherErr(e.to_string())
@@ -65,24 +178,30 @@ impl From<String> for TiTunError {
     }
 }
 
-// It is not possible to
-//
-// impl<T> From<T> TiTunError where T: Error {
-// }
-
-macro_rules! impl_from_err {
-    ($ty:ty) => (
-        impl From<$ty> for TiTunError
-        {
-            fn from(e: $ty) -> TiTunError {
-                TiTunError::OtherErr(e.description().to_string())
-            }
-        }
-    )
-}
+pub type Result<T> = ::std::result::Result<T, TiTunError>;
 
-impl_from_err!(::serde_yaml::Error);
-impl_from_err!(::std::net::AddrParseError);
-impl_from_err!(::log::SetLoggerError);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub type Result<T> = ::std::result::Result<T, TiTunError>;
+    #[test]
+    fn error_kinds() {
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+        let e = TiTunError::device("Failed to create tun device", denied());
+        assert_eq!(e.kind(), ErrorKind::Permission);
+        assert_eq!(e.exit_code(), 77);
+        assert!(e.cause().is_some());
+        assert_eq!(TiTunError::from(denied()).kind(), ErrorKind::Permission);
+
+        let e = TiTunError::network("Failed to bind to 0.0.0.0:1",
+                                    io::Error::from(io::ErrorKind::AddrInUse));
+        assert_eq!(e.kind(), ErrorKind::Network);
+        assert_eq!(e.to_string(), "Failed to bind to 0.0.0.0:1: address in use");
+
+        let e = TiTunError::from(::config::Config::parse("key: 1").unwrap_err());
+        assert_eq!(e.kind(), ErrorKind::Config);
+        assert_eq!(e.exit_code(), 78);
+        assert_eq!(TiTunError::from("other").exit_code(), 1);
+        assert_eq!(TiTunError::GracefulExit.exit_code(), 0);
+    }
+}
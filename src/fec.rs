@@ -0,0 +1,455 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// Forward error correction with a systematic Reed-Solomon erasure code over
+// GF(2^8). Data messages are sent as they are (wrapped in a small header),
+// and parity messages are computed over groups of them with a Cauchy
+// matrix, so that any `count` of the `count + parity` messages of a group
+// are enough to recover the lost data messages.
+//
+// Each data message is coded as a u16 length followed by the message,
+// padded with zeros to the longest one in the group.
+
+use byteorder::{BigEndian, ByteOrder};
+use message;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Extra bytes a parity message takes compared to the largest data message.
+pub const OVERHEAD: u32 = (message::FEC_PARITY_HEADER_LEN + 2) as u32;
+
+/// Maximum number of groups kept for recovery.
+const MAX_GROUPS: usize = 64;
+
+/// Discard groups after this long.
+const GROUP_TIMEOUT: u64 = 3;
+
+struct Gf {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Gf {
+    fn new() -> Gf {
+        let mut gf = Gf {
+            exp: [0; 512],
+            log: [0; 256],
+        };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            gf.exp[i] = gf.exp[i - 255];
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        debug_assert!(a != 0);
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    /// `dst += c * src`.
+    fn mul_add(&self, dst: &mut [u8], src: &[u8], c: u8) {
+        if c == 0 {
+            return;
+        }
+        let lc = self.log[c as usize] as usize;
+        for (d, &s) in dst.iter_mut().zip(src) {
+            if s != 0 {
+                *d ^= self.exp[lc + self.log[s as usize] as usize];
+            }
+        }
+    }
+
+    /// Coefficient of data message `i` in parity message `j`.
+    fn cauchy(&self, count: usize, j: usize, i: usize) -> u8 {
+        self.inv((count + j) as u8 ^ i as u8)
+    }
+
+    /// Invert a square matrix with Gauss-Jordan elimination.
+    fn invert(&self, mut a: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        let n = a.len();
+        let mut inv: Vec<Vec<u8>> = (0..n)
+            .map(|i| {
+                let mut r = vec![0u8; n];
+                r[i] = 1;
+                r
+            })
+            .collect();
+        for col in 0..n {
+            let pivot = match (col..n).find(|&r| a[r][col] != 0) {
+                Some(p) => p,
+                None => return None,
+            };
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let c = self.inv(a[col][col]);
+            for x in a[col].iter_mut().chain(inv[col].iter_mut()) {
+                *x = self.mul(*x, c);
+            }
+            let (a_col, inv_col) = (a[col].clone(), inv[col].clone());
+            for r in 0..n {
+                let f = a[r][col];
+                if r != col && f != 0 {
+                    self.mul_add(&mut a[r], &a_col, f);
+                    self.mul_add(&mut inv[r], &inv_col, f);
+                }
+            }
+        }
+        Some(inv)
+    }
+}
+
+fn shard(m: &[u8]) -> Vec<u8> {
+    let mut s = vec![0u8; 2];
+    BigEndian::write_u16(&mut s, m.len() as u16);
+    s.extend_from_slice(m);
+    s
+}
+
+/// Groups outgoing messages and computes parity messages for them.
+pub struct Encoder {
+    gf: Gf,
+    count: u8,
+    parity: u8,
+    group: u32,
+    shards: Vec<Vec<u8>>,
+}
+
+impl Encoder {
+    /// `count` data messages and `parity` parity messages per group.
+    /// `count + parity` must not exceed 255.
+    pub fn new(count: u8, parity: u8) -> Encoder {
+        assert!(count > 0 && parity > 0 && count as u32 + parity as u32 <= 255);
+        Encoder {
+            gf: Gf::new(),
+            count: count,
+            parity: parity,
+            group: 0,
+            shards: Vec::new(),
+        }
+    }
+
+    /// Wrap a message as a data message of the current group.
+    pub fn data(&mut self, m: &[u8]) -> Vec<u8> {
+        let index = self.shards.len() as u8;
+        self.shards.push(shard(m));
+        message::fec_data(self.group, index, m)
+    }
+
+    pub fn full(&self) -> bool {
+        self.shards.len() >= self.count as usize
+    }
+
+    /// Finish the current group, which may be smaller than `count`, and
+    /// return its parity messages.
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        if self.shards.is_empty() {
+            return Vec::new();
+        }
+        let count = self.shards.len();
+        let len = self.shards.iter().map(|s| s.len()).max().unwrap();
+        let parity = (0..self.parity as usize)
+            .map(|j| {
+                let mut p = vec![0u8; len];
+                for (i, s) in self.shards.iter().enumerate() {
+                    self.gf.mul_add(&mut p, s, self.gf.cauchy(count, j, i));
+                }
+                message::fec_parity(self.group, j as u8, count as u8, &p)
+            })
+            .collect();
+        self.shards.clear();
+        self.group = self.group.wrapping_add(1);
+        parity
+    }
+}
+
+struct Group {
+    /// Number of data messages, known once a parity message is received.
+    count: Option<u8>,
+    /// Data shards. Emptied, but kept as `Some`, once the group is done, to
+    /// recognize duplicates.
+    data: Vec<Option<Vec<u8>>>,
+    parity: Vec<(u8, Vec<u8>)>,
+    done: bool,
+    created: Instant,
+}
+
+impl Group {
+    fn missing(&self) -> usize {
+        match self.count {
+            Some(count) => {
+                (0..count as usize)
+                    .filter(|&i| self.data.get(i).map_or(true, |d| d.is_none()))
+                    .count()
+            }
+            None => 0,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.parity = Vec::new();
+        for d in &mut self.data {
+            if d.is_some() {
+                *d = Some(Vec::new());
+            }
+        }
+    }
+}
+
+/// Recovers lost data messages from parity messages.
+pub struct Decoder {
+    gf: Gf,
+    groups: HashMap<u32, Group>,
+    /// Number of data messages recovered.
+    pub recovered: u64,
+    /// Number of data messages that could not be recovered.
+    pub lost: u64,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            gf: Gf::new(),
+            groups: HashMap::new(),
+            recovered: 0,
+            lost: 0,
+        }
+    }
+
+    /// Add a data message. Returns the messages to deliver: this one, unless
+    /// it is a duplicate, and any recovered ones.
+    pub fn data(&mut self, group: u32, index: u8, m: &[u8], now: Instant) -> Vec<Vec<u8>> {
+        let gf = &self.gf;
+        let g = Self::group(&mut self.groups, &mut self.lost, group, now);
+        let i = index as usize;
+        if g.data.len() <= i {
+            g.data.resize(i + 1, None);
+        }
+        if g.data[i].is_some() {
+            return Vec::new();
+        }
+        g.data[i] = Some(if g.done { Vec::new() } else { shard(m) });
+        let mut out = vec![m.to_vec()];
+        out.extend(recover(gf, g, &mut self.recovered));
+        out
+    }
+
+    /// Add a parity message. Returns recovered messages.
+    pub fn parity(&mut self,
+                  group: u32,
+                  index: u8,
+                  count: u8,
+                  s: &[u8],
+                  now: Instant)
+                  -> Vec<Vec<u8>> {
+        if count == 0 || count as u32 + index as u32 >= 255 {
+            return Vec::new();
+        }
+        let gf = &self.gf;
+        let g = Self::group(&mut self.groups, &mut self.lost, group, now);
+        if g.done || g.count.map_or(false, |c| c != count) ||
+           g.parity.iter().any(|&(j, ref p)| j == index || p.len() != s.len()) {
+            return Vec::new();
+        }
+        g.count = Some(count);
+        g.parity.push((index, s.to_vec()));
+        recover(gf, g, &mut self.recovered)
+    }
+
+    fn group<'a>(groups: &'a mut HashMap<u32, Group>,
+                 lost: &mut u64,
+                 group: u32,
+                 now: Instant)
+                 -> &'a mut Group {
+        if !groups.contains_key(&group) {
+            let timeout = Duration::from_secs(GROUP_TIMEOUT);
+            let mut expired: Vec<u32> = groups.iter()
+                .filter(|&(_, g)| now.duration_since(g.created) > timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            if groups.len() - expired.len() >= MAX_GROUPS {
+                let oldest = groups.iter()
+                    .filter(|&(id, _)| !expired.contains(id))
+                    .min_by_key(|&(_, g)| g.created)
+                    .map(|(id, _)| *id);
+                expired.extend(oldest);
+            }
+            for id in expired {
+                let g = groups.remove(&id).unwrap();
+                if !g.done {
+                    *lost += g.missing() as u64;
+                }
+            }
+        }
+        groups.entry(group).or_insert_with(|| {
+            Group {
+                count: None,
+                data: Vec::new(),
+                parity: Vec::new(),
+                done: false,
+                created: now,
+            }
+        })
+    }
+}
+
+/// Recover missing data messages of a group, if possible.
+fn recover(gf: &Gf, g: &mut Group, recovered: &mut u64) -> Vec<Vec<u8>> {
+    let count = match g.count {
+        Some(c) if !g.done => c as usize,
+        _ => return Vec::new(),
+    };
+    let missing: Vec<usize> = (0..count)
+        .filter(|&i| g.data.get(i).map_or(true, |d| d.is_none()))
+        .collect();
+    if missing.is_empty() {
+        g.finish();
+        return Vec::new();
+    }
+    if count - missing.len() + g.parity.len() < count {
+        return Vec::new();
+    }
+
+    let len = g.parity[0].1.len();
+    let mut rows = Vec::with_capacity(count);
+    let mut shards: Vec<&[u8]> = Vec::with_capacity(count);
+    for i in 0..count {
+        if let Some(Some(ref d)) = g.data.get(i) {
+            if d.len() > len {
+                // Inconsistent with parity.
+                g.finish();
+                return Vec::new();
+            }
+            let mut r = vec![0u8; count];
+            r[i] = 1;
+            rows.push(r);
+            shards.push(d);
+        }
+    }
+    for &(j, ref p) in g.parity.iter().take(missing.len()) {
+        rows.push((0..count).map(|i| gf.cauchy(count, j as usize, i)).collect());
+        shards.push(p);
+    }
+    let inv = match gf.invert(rows) {
+        Some(inv) => inv,
+        None => {
+            g.finish();
+            return Vec::new();
+        }
+    };
+
+    let mut out = Vec::new();
+    for &i in &missing {
+        let mut d = vec![0u8; len];
+        for (r, s) in shards.iter().enumerate() {
+            gf.mul_add(&mut d, s, inv[i][r]);
+        }
+        let l = BigEndian::read_u16(&d) as usize;
+        if l + 2 <= len {
+            out.push(d[2..l + 2].to_vec());
+            *recovered += 1;
+        }
+    }
+    g.data.resize(::std::cmp::max(g.data.len(), count), None);
+    for &i in &missing {
+        g.data[i] = Some(Vec::new());
+    }
+    g.finish();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::{self, Message};
+    use std::time::Instant;
+
+    fn parse_data(m: &[u8]) -> (u32, u8, Vec<u8>) {
+        match message::parse(m) {
+            Some(Message::FecData { group, index, data }) => (group, index, data.to_vec()),
+            _ => panic!("not FEC data"),
+        }
+    }
+
+    fn parse_parity(m: &[u8]) -> (u32, u8, u8, Vec<u8>) {
+        match message::parse(m) {
+            Some(Message::FecParity { group, index, count, shard }) => {
+                (group, index, count, shard.to_vec())
+            }
+            _ => panic!("not FEC parity"),
+        }
+    }
+
+    #[test]
+    fn recovery() {
+        let now = Instant::now();
+        let messages: Vec<Vec<u8>> = (0..5u8).map(|i| vec![0x45; 10 + i as usize * 7]).collect();
+
+        let mut enc = Encoder::new(5, 2);
+        let data: Vec<_> = messages.iter().map(|m| enc.data(m)).collect();
+        assert!(enc.full());
+        let parity = enc.finish();
+        assert_eq!(parity.len(), 2);
+
+        // Lose two data messages.
+        let mut dec = Decoder::new();
+        let mut out = Vec::new();
+        for &i in &[0, 2, 4] {
+            let (g, idx, d) = parse_data(&data[i]);
+            out.extend(dec.data(g, idx, &d, now));
+        }
+        assert_eq!(out.len(), 3);
+        let (g, idx, c, s) = parse_parity(&parity[1]);
+        assert!(dec.parity(g, idx, c, &s, now).is_empty());
+        let (g, idx, c, s) = parse_parity(&parity[0]);
+        let recovered = dec.parity(g, idx, c, &s, now);
+        assert_eq!(recovered, vec![messages[1].clone(), messages[3].clone()]);
+        assert_eq!(dec.recovered, 2);
+
+        // Late duplicates are dropped.
+        let (g, idx, d) = parse_data(&data[1]);
+        assert!(dec.data(g, idx, &d, now).is_empty());
+
+        // A partial group, with only parity arriving before the data.
+        enc.data(&messages[0]);
+        let d1 = enc.data(&messages[1]);
+        let parity = enc.finish();
+        let (g, idx, c, s) = parse_parity(&parity[0]);
+        assert_eq!((g, c), (1, 2));
+        assert!(dec.parity(g, idx, c, &s, now).is_empty());
+        let (g, idx, d) = parse_data(&d1);
+        assert_eq!(dec.data(g, idx, &d, now),
+                   vec![messages[1].clone(), messages[0].clone()]);
+    }
+}
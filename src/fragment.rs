@@ -0,0 +1,154 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    created: Instant,
+}
+
+/// Reassembles packets fragmented by the peer. Incomplete packets are
+/// discarded after a timeout, or when they take more memory than allowed.
+pub struct Reassembler {
+    partials: HashMap<u32, Partial>,
+    bytes: usize,
+    max_bytes: usize,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new(max_bytes: usize, timeout: Duration) -> Reassembler {
+        Reassembler {
+            partials: HashMap::new(),
+            bytes: 0,
+            max_bytes: max_bytes,
+            timeout: timeout,
+        }
+    }
+
+    /// Add a fragment. Returns the reassembled packet if it is complete.
+    pub fn add(&mut self,
+               id: u32,
+               index: u8,
+               count: u8,
+               data: &[u8],
+               now: Instant)
+               -> Option<Vec<u8>> {
+        if index >= count {
+            return None;
+        }
+        self.expire(now);
+        while self.bytes + data.len() > self.max_bytes && self.evict_oldest() {}
+        if self.bytes + data.len() > self.max_bytes {
+            return None;
+        }
+
+        let complete = {
+            let p = self.partials.entry(id).or_insert_with(|| {
+                Partial {
+                    fragments: vec![None; count as usize],
+                    received: 0,
+                    bytes: 0,
+                    created: now,
+                }
+            });
+            if p.fragments.len() != count as usize || p.fragments[index as usize].is_some() {
+                // Inconsistent or duplicate.
+                return None;
+            }
+            p.fragments[index as usize] = Some(data.to_vec());
+            p.received += 1;
+            p.bytes += data.len();
+            self.bytes += data.len();
+            p.received == p.fragments.len()
+        };
+
+        if complete {
+            let p = self.partials.remove(&id).unwrap();
+            self.bytes -= p.bytes;
+            let mut out = Vec::with_capacity(p.bytes);
+            for f in p.fragments {
+                out.extend_from_slice(&f.unwrap());
+            }
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let expired: Vec<u32> = self.partials
+            .iter()
+            .filter(|&(_, p)| now.duration_since(p.created) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.partials.iter().min_by_key(|&(_, p)| p.created).map(|(id, _)| *id);
+        match oldest {
+            Some(id) => {
+                self.remove(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: u32) {
+        if let Some(p) = self.partials.remove(&id) {
+            self.bytes -= p.bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reassembly() {
+        let now = Instant::now();
+        let mut r = Reassembler::new(100, Duration::from_secs(3));
+
+        assert_eq!(r.add(1, 1, 2, &[3, 4], now), None);
+        assert_eq!(r.add(1, 1, 2, &[3, 4], now), None);
+        assert_eq!(r.add(1, 0, 2, &[1, 2], now), Some(vec![1, 2, 3, 4]));
+        assert_eq!(r.bytes, 0);
+
+        // Timeout.
+        assert_eq!(r.add(2, 0, 2, &[1], now), None);
+        assert_eq!(r.add(2, 1, 2, &[2], now + Duration::from_secs(4)), None);
+        assert_eq!(r.partials.len(), 1);
+
+        // Memory limit, the oldest packet is discarded.
+        let later = now + Duration::from_secs(5);
+        assert_eq!(r.add(3, 0, 2, &[0; 60], later), None);
+        assert_eq!(r.add(4, 0, 2, &[0; 60], later), None);
+        assert!(r.bytes <= 100);
+        assert_eq!(r.add(4, 1, 2, &[0; 10], later).map(|p| p.len()), Some(70));
+    }
+}
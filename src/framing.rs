@@ -0,0 +1,161 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// Outer framings that make encrypted packets look like some common
+// protocols to middleboxes. Only the headers are imitated, the payload is
+// still the output of `Crypto::encrypt`.
+
+use byteorder::{BigEndian, ByteOrder};
+use config::Framing;
+use sodiumoxide::randombytes::randombytes_into;
+use std::cell::Cell;
+
+/// DTLS 1.2 record header: content type, version, epoch, sequence number,
+/// length.
+const DTLS_HEADER_LEN: usize = 13;
+const DTLS_APPLICATION_DATA: u8 = 23;
+const DTLS_VERSION: [u8; 2] = [0xfe, 0xfd];
+
+/// QUIC short header: flags, 8 byte destination connection ID, 2 byte packet
+/// number.
+const QUIC_HEADER_LEN: usize = 11;
+
+/// RTP header: flags, payload type, sequence number, timestamp, SSRC.
+const RTP_HEADER_LEN: usize = 12;
+/// A dynamic payload type.
+const RTP_PAYLOAD_TYPE: u8 = 96;
+/// Timestamp increment per packet, as for 20ms of 48kHz audio.
+const RTP_TIMESTAMP_STEP: u32 = 960;
+
+pub struct Framer {
+    framing: Option<Framing>,
+    /// Connection ID or SSRC.
+    id: [u8; 8],
+    seq: Cell<u64>,
+}
+
+impl Framer {
+    pub fn new(framing: Option<Framing>) -> Framer {
+        let mut id = [0u8; 8];
+        randombytes_into(&mut id);
+        let mut seq = [0u8; 8];
+        randombytes_into(&mut seq);
+        Framer {
+            framing: framing,
+            id: id,
+            seq: Cell::new(BigEndian::read_u16(&seq) as u64),
+        }
+    }
+
+    /// Bytes added to each packet.
+    pub fn overhead(&self) -> usize {
+        match self.framing {
+            None => 0,
+            Some(Framing::Dtls) => DTLS_HEADER_LEN,
+            Some(Framing::Quic) => QUIC_HEADER_LEN,
+            Some(Framing::Rtp) => RTP_HEADER_LEN,
+        }
+    }
+
+    pub fn wrap(&self, p: Vec<u8>) -> Vec<u8> {
+        let framing = match self.framing {
+            None => return p,
+            Some(f) => f,
+        };
+        let seq = self.seq.get();
+        self.seq.set(seq.wrapping_add(1));
+
+        let mut m = vec![0u8; self.overhead()];
+        match framing {
+            Framing::Dtls => {
+                m[0] = DTLS_APPLICATION_DATA;
+                m[1..3].copy_from_slice(&DTLS_VERSION);
+                // Epoch 1, 48 bit sequence number.
+                BigEndian::write_u64(&mut m[3..11], (1 << 48) | (seq & 0xffff_ffff_ffff));
+                BigEndian::write_u16(&mut m[11..13], p.len() as u16);
+            }
+            Framing::Quic => {
+                // Fixed bit set, 2 byte packet number.
+                m[0] = 0x41;
+                m[1..9].copy_from_slice(&self.id);
+                BigEndian::write_u16(&mut m[9..11], seq as u16);
+            }
+            Framing::Rtp => {
+                // Version 2.
+                m[0] = 0x80;
+                m[1] = RTP_PAYLOAD_TYPE;
+                BigEndian::write_u16(&mut m[2..4], seq as u16);
+                BigEndian::write_u32(&mut m[4..8], (seq as u32).wrapping_mul(RTP_TIMESTAMP_STEP));
+                m[8..12].copy_from_slice(&self.id[..4]);
+            }
+        }
+        m.extend_from_slice(&p);
+        m
+    }
+
+    /// Strip the framing. Returns `None` if the packet does not look right.
+    pub fn unwrap<'a>(&self, m: &'a [u8]) -> Option<&'a [u8]> {
+        let framing = match self.framing {
+            None => return Some(m),
+            Some(f) => f,
+        };
+        if m.len() < self.overhead() {
+            return None;
+        }
+        let ok = match framing {
+            Framing::Dtls => {
+                m[0] == DTLS_APPLICATION_DATA && m[1..3] == DTLS_VERSION &&
+                BigEndian::read_u16(&m[11..13]) as usize == m.len() - DTLS_HEADER_LEN
+            }
+            Framing::Quic => m[0] & 0xc0 == 0x40,
+            Framing::Rtp => m[0] & 0xc0 == 0x80,
+        };
+        if ok { Some(&m[self.overhead()..]) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Framing;
+
+    #[test]
+    fn framings() {
+        let p = vec![1u8, 2, 3, 4, 5];
+        for &f in &[Framing::Dtls, Framing::Quic, Framing::Rtp] {
+            let framer = Framer::new(Some(f));
+            let m = framer.wrap(p.clone());
+            assert_eq!(m.len(), p.len() + framer.overhead());
+            assert_eq!(framer.unwrap(&m), Some(&p[..]));
+            assert_eq!(framer.unwrap(&p), None);
+        }
+
+        let dtls = Framer::new(Some(Framing::Dtls));
+        let m = dtls.wrap(p.clone());
+        assert_eq!(&m[..5], &[23, 0xfe, 0xfd, 0, 1]);
+        assert_eq!(dtls.unwrap(&m[..m.len() - 1]), None);
+
+        let rtp = Framer::new(Some(Framing::Rtp));
+        let m1 = rtp.wrap(p.clone());
+        let m2 = rtp.wrap(p.clone());
+        assert_eq!(BigEndian::read_u16(&m2[2..4]),
+                   BigEndian::read_u16(&m1[2..4]).wrapping_add(1));
+
+        let none = Framer::new(None);
+        assert_eq!(none.wrap(p.clone()), p);
+    }
+}
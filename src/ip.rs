@@ -0,0 +1,457 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// Minimal inspection of IPv4/IPv6 packets read from the tun device. These
+// functions never panic on malformed input, they just return `None`.
+
+use byteorder::{BigEndian, ByteOrder};
+use mtu::MIN_MTU;
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+pub const PROTO_ICMPV6: u8 = 58;
+
+pub const ECN_NOT_ECT: u8 = 0;
+pub const ECN_ECT1: u8 = 1;
+pub const ECN_ECT0: u8 = 2;
+pub const ECN_CE: u8 = 3;
+
+/// IP version of the packet, 4 or 6.
+pub fn version(p: &[u8]) -> Option<u8> {
+    match p.first().map(|b| b >> 4) {
+        Some(4) if p.len() >= 20 => Some(4),
+        Some(6) if p.len() >= 40 => Some(6),
+        _ => None,
+    }
+}
+
+/// The IPv4 TOS / IPv6 traffic class byte.
+pub fn traffic_class(p: &[u8]) -> Option<u8> {
+    match version(p) {
+        Some(4) => Some(p[1]),
+        Some(6) => Some((p[0] << 4) | (p[1] >> 4)),
+        _ => None,
+    }
+}
+
+/// Set the IPv4 TOS / IPv6 traffic class byte, fixing up the IPv4 header
+/// checksum.
+pub fn set_traffic_class(p: &mut [u8], tc: u8) {
+    match version(p) {
+        Some(4) => {
+            p[1] = tc;
+            update_ipv4_header_checksum(p);
+        }
+        Some(6) => {
+            p[0] = 0x60 | (tc >> 4);
+            p[1] = (p[1] & 0x0f) | (tc << 4);
+        }
+        _ => {}
+    }
+}
+
+/// Combine the ECN field of a decapsulated packet with the ECN field of the
+/// outer header, as specified by RFC 6040.
+///
+/// Returns false if the packet must be dropped, i.e. the outer header is
+/// marked CE but the inner packet is not ECN capable.
+pub fn decapsulate_ecn(p: &mut [u8], outer: u8) -> bool {
+    let tc = match traffic_class(p) {
+        Some(tc) => tc,
+        None => return true,
+    };
+    let new = match (tc & 3, outer & 3) {
+        (ECN_NOT_ECT, ECN_CE) => return false,
+        (ECN_NOT_ECT, _) => return true,
+        (_, ECN_CE) => ECN_CE,
+        (ECN_ECT0, ECN_ECT1) => ECN_ECT1,
+        (inner, _) => inner,
+    };
+    if new != tc & 3 {
+        set_traffic_class(p, (tc & !3) | new);
+    }
+    true
+}
+
+/// DSCP, i.e. the upper 6 bits of the traffic class.
+pub fn dscp(p: &[u8]) -> Option<u8> {
+    traffic_class(p).map(|tc| tc >> 2)
+}
+
+/// Source and destination addresses, as raw bytes.
+pub fn addresses(p: &[u8]) -> Option<(&[u8], &[u8])> {
+    match version(p) {
+        Some(4) => Some((&p[12..16], &p[16..20])),
+        Some(6) => Some((&p[8..24], &p[24..40])),
+        _ => None,
+    }
+}
+
+/// Transport protocol and offset of the transport header.
+///
+/// Returns `None` for non-first fragments. Common IPv6 extension headers
+/// are skipped.
+pub fn transport(p: &[u8]) -> Option<(u8, usize)> {
+    match version(p) {
+        Some(4) => {
+            let ihl = ((p[0] & 0x0f) as usize) * 4;
+            let frag_offset = BigEndian::read_u16(&p[6..8]) & 0x1fff;
+            if ihl < 20 || ihl > p.len() || frag_offset != 0 {
+                None
+            } else {
+                Some((p[9], ihl))
+            }
+        }
+        Some(6) => {
+            let mut next = p[6];
+            let mut offset = 40;
+            loop {
+                match next {
+                    // Hop-by-hop, routing, destination options.
+                    0 | 43 | 60 => {
+                        if p.len() < offset + 8 {
+                            return None;
+                        }
+                        next = p[offset];
+                        offset += (p[offset + 1] as usize + 1) * 8;
+                    }
+                    // Fragment.
+                    44 => {
+                        if p.len() < offset + 8 ||
+                           BigEndian::read_u16(&p[offset + 2..offset + 4]) & 0xfff8 != 0 {
+                            return None;
+                        }
+                        next = p[offset];
+                        offset += 8;
+                    }
+                    _ => return Some((next, offset)),
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Source and destination ports of a TCP or UDP packet.
+pub fn ports(p: &[u8]) -> Option<(u16, u16)> {
+    match transport(p) {
+        Some((PROTO_TCP, o)) |
+        Some((PROTO_UDP, o)) if p.len() >= o + 4 => {
+            Some((BigEndian::read_u16(&p[o..o + 2]), BigEndian::read_u16(&p[o + 2..o + 4])))
+        }
+        _ => None,
+    }
+}
+
+/// Whether this is a TCP segment that only acknowledges data, i.e. has the
+/// ACK flag, no SYN/FIN/RST and no payload.
+pub fn is_pure_tcp_ack(p: &[u8]) -> bool {
+    match transport(p) {
+        Some((PROTO_TCP, o)) if p.len() >= o + 20 => {
+            let data_offset = ((p[o + 12] >> 4) as usize) * 4;
+            let flags = p[o + 13];
+            // ACK set, SYN/FIN/RST unset.
+            flags & 0x17 == 0x10 && o + data_offset >= p.len()
+        }
+        _ => false,
+    }
+}
+
+/// Clamp the MSS option of a TCP SYN / SYN-ACK segment to at most `mss`,
+/// fixing up the TCP checksum. Returns true if the packet is changed.
+pub fn clamp_mss(p: &mut [u8], mss: u16) -> bool {
+    let o = match transport(p) {
+        Some((PROTO_TCP, o)) if p.len() >= o + 20 => o,
+        _ => return false,
+    };
+    // SYN.
+    if p[o + 13] & 0x02 == 0 {
+        return false;
+    }
+    let end = ::std::cmp::min(o + ((p[o + 12] >> 4) as usize) * 4, p.len());
+    let mut i = o + 20;
+    while i < end {
+        match p[i] {
+            // End of options.
+            0 => return false,
+            // No-op.
+            1 => i += 1,
+            kind => {
+                if i + 1 >= end || p[i + 1] < 2 {
+                    return false;
+                }
+                let len = p[i + 1] as usize;
+                if kind == 2 && len == 4 && i + 4 <= end {
+                    if BigEndian::read_u16(&p[i + 2..i + 4]) <= mss {
+                        return false;
+                    }
+                    BigEndian::write_u16(&mut p[i + 2..i + 4], mss);
+                    update_tcp_checksum(p, o);
+                    return true;
+                }
+                i += len;
+            }
+        }
+    }
+    false
+}
+
+fn update_tcp_checksum(p: &mut [u8], o: usize) {
+    p[o + 16] = 0;
+    p[o + 17] = 0;
+    let len = (p.len() - o) as u32;
+    let c = checksum(&p[o..], pseudo_header_sum(p, PROTO_TCP, len));
+    BigEndian::write_u16(&mut p[o + 16..o + 18], c);
+}
+
+/// Whether the packet is an ICMP / ICMPv6 error message. Errors must not be
+/// sent in response to these.
+fn is_icmp_error(p: &[u8]) -> bool {
+    match transport(p) {
+        Some((PROTO_ICMP, o)) if p.len() > o => {
+            match p[o] {
+                3 | 4 | 5 | 11 | 12 => true,
+                _ => false,
+            }
+        }
+        Some((PROTO_ICMPV6, o)) if p.len() > o => p[o] < 128,
+        _ => false,
+    }
+}
+
+/// Build an ICMP "fragmentation needed" or ICMPv6 "packet too big" message
+/// in response to `p`, which is larger than `mtu`.
+///
+/// Returns `None` if no such message should be sent, i.e. `p` is an IPv4
+/// packet without the DF flag, an ICMP error, or not a valid IP packet.
+pub fn icmp_too_big(p: &[u8], mtu: u32) -> Option<Vec<u8>> {
+    if is_icmp_error(p) {
+        return None;
+    }
+    match version(p) {
+        Some(4) => {
+            let df = p[6] & 0x40 != 0;
+            let ihl = ((p[0] & 0x0f) as usize) * 4;
+            if !df || ihl < 20 || ihl > p.len() {
+                return None;
+            }
+            // Original header and 8 bytes of payload.
+            let quote = &p[..::std::cmp::min(p.len(), ihl + 8)];
+            let mut m = vec![0u8; 20 + 8 + quote.len()];
+            m[0] = 0x45;
+            let len = m.len() as u16;
+            BigEndian::write_u16(&mut m[2..4], len);
+            m[8] = 64;
+            m[9] = PROTO_ICMP;
+            // From the original destination to the original source.
+            m[12..16].copy_from_slice(&p[16..20]);
+            m[16..20].copy_from_slice(&p[12..16]);
+            update_ipv4_header_checksum(&mut m);
+
+            m[20] = 3;
+            m[21] = 4;
+            BigEndian::write_u16(&mut m[26..28], ::std::cmp::min(mtu, 0xffff) as u16);
+            m[28..].copy_from_slice(quote);
+            let c = checksum(&m[20..], 0);
+            BigEndian::write_u16(&mut m[22..24], c);
+            Some(m)
+        }
+        Some(6) => {
+            // The whole message must fit in the minimum IPv6 MTU.
+            let quote = &p[..::std::cmp::min(p.len(), MIN_MTU as usize - 48)];
+            let mut m = vec![0u8; 48 + quote.len()];
+            m[0] = 0x60;
+            let len = (m.len() - 40) as u16;
+            BigEndian::write_u16(&mut m[4..6], len);
+            m[6] = PROTO_ICMPV6;
+            m[7] = 64;
+            m[8..24].copy_from_slice(&p[24..40]);
+            m[24..40].copy_from_slice(&p[8..24]);
+
+            m[40] = 2;
+            BigEndian::write_u32(&mut m[44..48], mtu);
+            m[48..].copy_from_slice(quote);
+            let c = checksum(&m[40..], pseudo_header_sum(&m, PROTO_ICMPV6, len as u32));
+            BigEndian::write_u16(&mut m[42..44], c);
+            Some(m)
+        }
+        _ => None,
+    }
+}
+
+/// Partial checksum of the pseudo header used by TCP, UDP and ICMPv6.
+fn pseudo_header_sum(p: &[u8], proto: u8, len: u32) -> u32 {
+    let mut sum = 0u32;
+    if let Some((src, dst)) = addresses(p) {
+        for c in src.chunks(2).chain(dst.chunks(2)) {
+            sum += BigEndian::read_u16(c) as u32;
+        }
+    }
+    sum + proto as u32 + (len >> 16) + (len & 0xffff)
+}
+
+/// Internet checksum of `data`, starting from a partial sum.
+pub fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    for c in data.chunks(2) {
+        sum += if c.len() == 2 {
+            BigEndian::read_u16(c) as u32
+        } else {
+            (c[0] as u32) << 8
+        };
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn update_ipv4_header_checksum(p: &mut [u8]) {
+    let ihl = ((p[0] & 0x0f) as usize) * 4;
+    if ihl < 20 || ihl > p.len() {
+        return;
+    }
+    p[10] = 0;
+    p[11] = 0;
+    let c = checksum(&p[..ihl], 0);
+    BigEndian::write_u16(&mut p[10..12], c);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// An IPv4 TCP packet with the given TOS, ports, flags and payload length.
+    pub fn tcp4(tos: u8, sport: u16, dport: u16, flags: u8, payload: usize) -> Vec<u8> {
+        let mut p = vec![0u8; 40 + payload];
+        p[0] = 0x45;
+        p[1] = tos;
+        let len = p.len() as u16;
+        BigEndian::write_u16(&mut p[2..4], len);
+        p[8] = 64;
+        p[9] = PROTO_TCP;
+        p[12..16].copy_from_slice(&[192, 0, 2, 1]);
+        p[16..20].copy_from_slice(&[192, 0, 2, 2]);
+        BigEndian::write_u16(&mut p[20..22], sport);
+        BigEndian::write_u16(&mut p[22..24], dport);
+        p[32] = 5 << 4;
+        p[33] = flags;
+        p
+    }
+
+    /// An IPv6 UDP packet with the given traffic class, ports and payload length.
+    pub fn udp6(tc: u8, sport: u16, dport: u16, payload: usize) -> Vec<u8> {
+        let mut p = vec![0u8; 48 + payload];
+        p[0] = 0x60 | (tc >> 4);
+        p[1] = tc << 4;
+        let len = (p.len() - 40) as u16;
+        BigEndian::write_u16(&mut p[4..6], len);
+        p[6] = PROTO_UDP;
+        p[7] = 64;
+        p[8] = 0x20;
+        p[9] = 0x01;
+        p[24] = 0x20;
+        p[25] = 0x01;
+        p[39] = 1;
+        BigEndian::write_u16(&mut p[40..42], sport);
+        BigEndian::write_u16(&mut p[42..44], dport);
+        p
+    }
+
+    #[test]
+    fn inspect_packets() {
+        let p = tcp4(0xb8, 22, 40000, 0x10, 0);
+        assert_eq!(version(&p), Some(4));
+        assert_eq!(dscp(&p), Some(46));
+        assert_eq!(ports(&p), Some((22, 40000)));
+        assert!(is_pure_tcp_ack(&p));
+        assert!(!is_pure_tcp_ack(&tcp4(0, 22, 40000, 0x18, 100)));
+
+        let p = udp6(0x20, 5060, 5061, 10);
+        assert_eq!(version(&p), Some(6));
+        assert_eq!(dscp(&p), Some(8));
+        assert_eq!(ports(&p), Some((5060, 5061)));
+        assert!(!is_pure_tcp_ack(&p));
+
+        assert_eq!(version(&[0x45, 0, 0]), None);
+        assert_eq!(ports(&[0x10; 60]), None);
+    }
+
+    #[test]
+    fn icmp_too_big_messages() {
+        let mut p = tcp4(0, 1, 2, 0x18, 1400);
+        assert_eq!(icmp_too_big(&p, 1400), None);
+        // Set DF.
+        p[6] = 0x40;
+        let m = icmp_too_big(&p, 1400).unwrap();
+        assert_eq!(m.len(), 20 + 8 + 28);
+        assert_eq!(addresses(&m), Some((&p[16..20], &p[12..16])));
+        assert_eq!(checksum(&m[..20], 0), 0);
+        assert_eq!(checksum(&m[20..], 0), 0);
+        assert_eq!(BigEndian::read_u16(&m[26..28]), 1400);
+        assert_eq!(icmp_too_big(&m, 500), None);
+
+        let p = udp6(0, 1, 2, 1500);
+        let m = icmp_too_big(&p, 1400).unwrap();
+        assert_eq!(m.len(), 1280);
+        let len = m.len() as u32 - 40;
+        assert_eq!(checksum(&m[40..], pseudo_header_sum(&m, PROTO_ICMPV6, len)), 0);
+        assert_eq!(BigEndian::read_u32(&m[44..48]), 1400);
+        assert_eq!(icmp_too_big(&m, 1400), None);
+    }
+
+    #[test]
+    fn mss_clamping() {
+        // SYN with MSS 1460.
+        let mut p = tcp4(0, 1, 2, 0x02, 4);
+        p[32] = 6 << 4;
+        p[40..44].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+        update_tcp_checksum(&mut p, 20);
+
+        assert!(!clamp_mss(&mut p, 1460));
+        assert!(clamp_mss(&mut p, 1360));
+        assert_eq!(BigEndian::read_u16(&p[42..44]), 1360);
+        let len = p.len() as u32 - 20;
+        assert_eq!(checksum(&p[20..], pseudo_header_sum(&p, PROTO_TCP, len)), 0);
+
+        // Not SYN.
+        p[33] = 0x10;
+        assert!(!clamp_mss(&mut p, 1200));
+    }
+
+    #[test]
+    fn ecn_decapsulation() {
+        let mut p = tcp4(ECN_ECT0, 1, 2, 0x10, 0);
+        assert!(decapsulate_ecn(&mut p, ECN_CE));
+        assert_eq!(traffic_class(&p), Some(ECN_CE));
+        assert_eq!(checksum(&p[..20], 0), 0);
+
+        let mut p = tcp4(0xb8 | ECN_ECT0, 1, 2, 0x10, 0);
+        assert!(decapsulate_ecn(&mut p, ECN_ECT1));
+        assert_eq!(traffic_class(&p), Some(0xb8 | ECN_ECT1));
+
+        let mut p = udp6(ECN_NOT_ECT, 1, 2, 0);
+        assert!(!decapsulate_ecn(&mut p, ECN_CE));
+        assert!(decapsulate_ecn(&mut p, ECN_ECT0));
+        assert_eq!(traffic_class(&p), Some(ECN_NOT_ECT));
+
+        let mut p = udp6(0x20 | ECN_ECT1, 1, 2, 0);
+        assert!(decapsulate_ecn(&mut p, ECN_CE));
+        assert_eq!(traffic_class(&p), Some(0x20 | ECN_CE));
+    }
+}
@@ -20,21 +20,36 @@ extern crate data_encoding;
 extern crate futures;
 #[macro_use]
 extern crate log;
+extern crate lz4_flex;
 extern crate mio;
 #[macro_use]
 extern crate nix;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_yaml;
 extern crate sodiumoxide;
-#[macro_use]
 extern crate tokio_core;
 extern crate tokio_signal;
+extern crate yaml_rust;
 
+pub mod check;
 pub mod config;
+pub mod control;
 pub mod crypto;
 pub mod error;
+mod fec;
+mod fragment;
+mod framing;
+mod ip;
+mod message;
+pub mod metrics;
+mod mtu;
+mod netlink;
+mod scheduler;
 mod script_runner;
+pub mod stats;
 mod systemd;
 pub mod titun;
 pub mod tun;
+mod udp;
@@ -15,55 +15,156 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
+#[macro_use]
 extern crate clap;
 extern crate env_logger;
+extern crate nix;
+extern crate serde_json;
 extern crate sodiumoxide;
 extern crate titun;
 
 use clap::{App, AppSettings, SubCommand};
-use std::fs::File;
-use std::io::Read;
-use titun::config::{Config, genkey_base64};
-use titun::error::Result;
+use nix::sys::termios;
+use nix::unistd::isatty;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use titun::check::check;
+use titun::config::{Config, encode_key, gen_private_key_base64, genkey_base64,
+                    public_key_base64};
+use titun::control;
+use titun::crypto::{DEFAULT_PWHASH_MEM, DEFAULT_PWHASH_OPS, DEFAULT_PWHASH_SALT, MAX_PWHASH_MEM,
+                    derive_key};
+use titun::error::{Result, TiTunError};
 use titun::titun::run;
 
-fn read_file(path: &str) -> Result<String> {
-    let mut f = File::open(path)?;
-    let mut out = String::new();
-    f.read_to_string(&mut out)?;
-    Ok(out)
-}
-
 fn inner() -> Result<()> {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
     }
     env_logger::init()?;
-    sodiumoxide::init();
+    if !sodiumoxide::init() {
+        return Err(TiTunError::CryptoErr("failed to initialize libsodium".to_string()));
+    }
 
     let sub_tun = SubCommand::with_name("tun")
         .display_order(1)
         .args_from_usage("-c, --config=<FILE> 'Specify config file'");
-    let sub_genkey = SubCommand::with_name("genkey").display_order(2);
+    let sub_genkey = SubCommand::with_name("genkey")
+        .display_order(2)
+        .about("Generate a random key or private key, or derive a key from a passphrase")
+        .args_from_usage("--private 'Generate an X25519 private key'
+                          --from-passphrase 'Derive the key from a passphrase read from stdin'
+                          --salt=[SALT] 'Salt for key derivation'
+                          --ops=[N] 'Argon2id iterations'
+                          --mem=[MiB] 'Argon2id memory in MiB'");
+    let sub_pubkey = SubCommand::with_name("pubkey")
+        .display_order(3)
+        .about("Print the public key of a private key read from stdin");
+    let sub_show = SubCommand::with_name("show")
+        .display_order(4)
+        .about("Show status of running tunnels")
+        .args_from_usage("[tunnel] 'Name of the tun device, all tunnels if not specified'
+                          --json 'Output JSON'");
+
+    let sub_check = SubCommand::with_name("check")
+        .display_order(5)
+        .about("Check a config file and the environment without starting the tunnel")
+        .args_from_usage("-c, --config=<FILE> 'Specify config file'");
 
     let app = App::new("titun")
         .version("0.0.4")
         .about("A simple secure ip tunnel for linux.")
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .subcommand(sub_tun)
-        .subcommand(sub_genkey);
+        .subcommand(sub_genkey)
+        .subcommand(sub_pubkey)
+        .subcommand(sub_show)
+        .subcommand(sub_check);
 
     let matches = app.get_matches();
 
     match matches.subcommand() {
+        ("genkey", Some(m)) if m.is_present("private") => {
+            println!("private_key: \"{}\"", gen_private_key_base64());
+        }
+        ("genkey", Some(m)) if m.is_present("from-passphrase") => {
+            let ops = if m.is_present("ops") {
+                value_t!(m, "ops", u64).unwrap_or_else(|e| e.exit())
+            } else {
+                DEFAULT_PWHASH_OPS
+            };
+            let mem = if m.is_present("mem") {
+                value_t!(m, "mem", u64).unwrap_or_else(|e| e.exit())
+            } else {
+                DEFAULT_PWHASH_MEM
+            };
+            if ops == 0 {
+                return Err(From::from("--ops must be at least 1"));
+            }
+            if mem == 0 || mem > MAX_PWHASH_MEM {
+                return Err(From::from(format!("--mem must be between 1 and {} (MiB)",
+                                              MAX_PWHASH_MEM)));
+            }
+            let salt = m.value_of("salt").unwrap_or(DEFAULT_PWHASH_SALT);
+            let passphrase = read_passphrase()?;
+            if passphrase.is_empty() {
+                return Err(From::from("passphrase is empty"));
+            }
+            let key = derive_key(passphrase.as_bytes(), salt, ops, mem)
+                .ok_or_else(|| TiTunError::CryptoErr("key derivation failed".to_string()))?;
+            println!("key: \"{}\"", encode_key(&key));
+        }
         ("genkey", _) => {
             println!("key: \"{}\"", genkey_base64());
         }
+        ("pubkey", _) => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            let public = public_key_base64(&input)
+                .ok_or_else(|| TiTunError::CryptoErr("invalid private key".to_string()))?;
+            println!("public_key: \"{}\"", public);
+        }
         ("tun", Some(m)) => {
             let config_file = m.value_of("config").unwrap();
-            let config = read_file(config_file)?;
-            let config = Config::parse(config.as_str())?;
-            run(&config)?;
+            let config = Config::load(config_file)?;
+            run(&config, Some(Path::new(config_file)))?;
+        }
+        ("show", Some(m)) => {
+            let names = match m.value_of("tunnel") {
+                Some(t) => vec![t.to_string()],
+                None => control::list()?,
+            };
+            let mut statuses = Vec::new();
+            for n in &names {
+                let path = control::socket_path(n);
+                statuses.push(control::query(&path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?);
+            }
+            if m.is_present("json") {
+                let json = if m.value_of("tunnel").is_some() {
+                    serde_json::to_string_pretty(&statuses[0])?
+                } else {
+                    serde_json::to_string_pretty(&statuses)?
+                };
+                println!("{}", json);
+            } else {
+                for (i, s) in statuses.iter().enumerate() {
+                    if i > 0 {
+                        println!("");
+                    }
+                    println!("{}", s);
+                }
+            }
+        }
+        ("check", Some(m)) => {
+            let checks = check(Path::new(m.value_of("config").unwrap()));
+            for c in &checks {
+                println!("{}", c);
+            }
+            let failed = checks.iter().filter(|c| !c.ok).count();
+            if failed > 0 {
+                return Err(From::from(format!("{} check(s) failed", failed)));
+            }
         }
         _ => {
             unreachable!();
@@ -73,9 +174,32 @@ fn inner() -> Result<()> {
     Ok(())
 }
 
+/// Read a line from stdin, without echo if it is a terminal.
+fn read_passphrase() -> Result<String> {
+    let tty = isatty(0).unwrap_or(false);
+    let saved = if tty {
+        write!(io::stderr(), "Passphrase: ")?;
+        let saved = termios::tcgetattr(0).map_err(|e| e.to_string())?;
+        let mut t = saved;
+        t.c_lflag.remove(termios::ECHO);
+        termios::tcsetattr(0, termios::TCSANOW, &t).map_err(|e| e.to_string())?;
+        Some(saved)
+    } else {
+        None
+    };
+    let mut line = String::new();
+    let result = io::stdin().lock().read_line(&mut line);
+    if let Some(saved) = saved {
+        let _ = termios::tcsetattr(0, termios::TCSANOW, &saved);
+        let _ = writeln!(io::stderr(), "");
+    }
+    result?;
+    Ok(line.lines().next().unwrap_or("").to_string())
+}
+
 fn main() {
     inner().unwrap_or_else(|e| {
         println!("Error: {}", e);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     })
 }
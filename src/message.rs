@@ -0,0 +1,277 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// What is encrypted and sent to the peer is either an IP packet, or a control
+// message. They are distinguished by the first byte: the version nibble of an
+// IP packet is 4 or 6, control messages use other values.
+
+use byteorder::{BigEndian, ByteOrder};
+use lz4_flex;
+
+/// Path MTU probe: type, u16 probe size, padding.
+const PROBE: u8 = 0x01;
+/// Probe acknowledgement: type, u16 probe size.
+const PROBE_ACK: u8 = 0x02;
+/// Fragment of a packet: type, u32 id, u8 index, u8 count, data.
+const FRAGMENT: u8 = 0x03;
+
+/// FEC data: type, u32 group, u8 index, message.
+const FEC_DATA: u8 = 0x04;
+/// FEC parity: type, u32 group, u8 index, u8 number of data messages, shard.
+const FEC_PARITY: u8 = 0x05;
+/// LZ4 compressed message: type, u16 uncompressed length, LZ4 block.
+const COMPRESSED: u8 = 0x06;
+/// Padded message: type, u16 message length, message, padding.
+const PADDED: u8 = 0x07;
+
+/// Dummy message, discarded by the receiver: type.
+const DUMMY: u8 = 0x08;
+
+pub const PADDING_HEADER_LEN: usize = 3;
+
+pub const FRAGMENT_HEADER_LEN: usize = 7;
+pub const FEC_DATA_HEADER_LEN: usize = 6;
+pub const FEC_PARITY_HEADER_LEN: usize = 7;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    Packet(&'a [u8]),
+    Probe(u16),
+    ProbeAck(u16),
+    Fragment {
+        id: u32,
+        index: u8,
+        count: u8,
+        data: &'a [u8],
+    },
+    FecData {
+        group: u32,
+        index: u8,
+        data: &'a [u8],
+    },
+    FecParity {
+        group: u32,
+        index: u8,
+        count: u8,
+        shard: &'a [u8],
+    },
+    Compressed {
+        len: u16,
+        data: &'a [u8],
+    },
+    Padded(&'a [u8]),
+    Dummy,
+}
+
+pub fn parse<'a>(m: &'a [u8]) -> Option<Message<'a>> {
+    match m.first().map(|b| *b) {
+        Some(b) if b >> 4 == 4 || b >> 4 == 6 => Some(Message::Packet(m)),
+        Some(PROBE) if m.len() >= 3 => Some(Message::Probe(BigEndian::read_u16(&m[1..3]))),
+        Some(PROBE_ACK) if m.len() >= 3 => {
+            Some(Message::ProbeAck(BigEndian::read_u16(&m[1..3])))
+        }
+        Some(FRAGMENT) if m.len() >= FRAGMENT_HEADER_LEN => {
+            Some(Message::Fragment {
+                id: BigEndian::read_u32(&m[1..5]),
+                index: m[5],
+                count: m[6],
+                data: &m[FRAGMENT_HEADER_LEN..],
+            })
+        }
+        Some(FEC_DATA) if m.len() >= FEC_DATA_HEADER_LEN => {
+            Some(Message::FecData {
+                group: BigEndian::read_u32(&m[1..5]),
+                index: m[5],
+                data: &m[FEC_DATA_HEADER_LEN..],
+            })
+        }
+        Some(FEC_PARITY) if m.len() >= FEC_PARITY_HEADER_LEN => {
+            Some(Message::FecParity {
+                group: BigEndian::read_u32(&m[1..5]),
+                index: m[5],
+                count: m[6],
+                shard: &m[FEC_PARITY_HEADER_LEN..],
+            })
+        }
+        Some(COMPRESSED) if m.len() >= 3 => {
+            Some(Message::Compressed {
+                len: BigEndian::read_u16(&m[1..3]),
+                data: &m[3..],
+            })
+        }
+        Some(DUMMY) => Some(Message::Dummy),
+        Some(PADDED) if m.len() >= PADDING_HEADER_LEN => {
+            let len = BigEndian::read_u16(&m[1..3]) as usize;
+            if m.len() < PADDING_HEADER_LEN + len {
+                return None;
+            }
+            Some(Message::Padded(&m[PADDING_HEADER_LEN..PADDING_HEADER_LEN + len]))
+        }
+        _ => None,
+    }
+}
+
+/// A probe for outer packet size `size`, padded to `len` bytes.
+pub fn probe(size: u16, len: usize) -> Vec<u8> {
+    let mut m = vec![0u8; ::std::cmp::max(len, 3)];
+    m[0] = PROBE;
+    BigEndian::write_u16(&mut m[1..3], size);
+    m
+}
+
+pub fn probe_ack(size: u16) -> Vec<u8> {
+    let mut m = vec![PROBE_ACK, 0, 0];
+    BigEndian::write_u16(&mut m[1..3], size);
+    m
+}
+
+/// Split `p` into fragments of at most `max_len` bytes, including the
+/// fragment header. Returns `None` if that takes more than 255 fragments.
+pub fn fragments(id: u32, p: &[u8], max_len: usize) -> Option<Vec<Vec<u8>>> {
+    if max_len <= FRAGMENT_HEADER_LEN {
+        return None;
+    }
+    let chunks: Vec<_> = p.chunks(max_len - FRAGMENT_HEADER_LEN).collect();
+    if chunks.len() > 255 {
+        return None;
+    }
+    let count = chunks.len() as u8;
+    Some(chunks.into_iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let mut m = vec![FRAGMENT, 0, 0, 0, 0, i as u8, count];
+            BigEndian::write_u32(&mut m[1..5], id);
+            m.extend_from_slice(c);
+            m
+        })
+        .collect())
+}
+
+pub fn fec_data(group: u32, index: u8, data: &[u8]) -> Vec<u8> {
+    let mut m = vec![FEC_DATA, 0, 0, 0, 0, index];
+    BigEndian::write_u32(&mut m[1..5], group);
+    m.extend_from_slice(data);
+    m
+}
+
+pub fn fec_parity(group: u32, index: u8, count: u8, shard: &[u8]) -> Vec<u8> {
+    let mut m = vec![FEC_PARITY, 0, 0, 0, 0, index, count];
+    BigEndian::write_u32(&mut m[1..5], group);
+    m.extend_from_slice(shard);
+    m
+}
+
+/// Compress a message. Returns `None` if that does not make it smaller.
+pub fn compress(m: &[u8]) -> Option<Vec<u8>> {
+    if m.len() > 0xffff {
+        return None;
+    }
+    let data = lz4_flex::compress(m);
+    if data.len() + 3 >= m.len() {
+        return None;
+    }
+    let mut c = vec![COMPRESSED, 0, 0];
+    BigEndian::write_u16(&mut c[1..3], m.len() as u16);
+    c.extend_from_slice(&data);
+    Some(c)
+}
+
+pub fn decompress(len: u16, data: &[u8]) -> Option<Vec<u8>> {
+    lz4_flex::decompress(data, len as usize).ok()
+}
+
+pub fn dummy() -> Vec<u8> {
+    vec![DUMMY]
+}
+
+/// Pad a message to `size` bytes, or as little as possible if it does not
+/// fit.
+pub fn pad(m: &[u8], size: usize) -> Vec<u8> {
+    let mut p = Vec::with_capacity(::std::cmp::max(size, m.len() + PADDING_HEADER_LEN));
+    p.extend_from_slice(&[PADDED, 0, 0]);
+    BigEndian::write_u16(&mut p[1..3], m.len() as u16);
+    p.extend_from_slice(m);
+    if p.len() < size {
+        p.resize(size, 0);
+    }
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_messages() {
+        let p = probe(1400, 1332);
+        assert_eq!(p.len(), 1332);
+        assert_eq!(parse(&p), Some(Message::Probe(1400)));
+        assert_eq!(parse(&probe_ack(1400)), Some(Message::ProbeAck(1400)));
+        assert_eq!(parse(&[0x45, 0, 0]), Some(Message::Packet(&[0x45, 0, 0])));
+        assert_eq!(parse(&[PROBE]), None);
+        assert_eq!(parse(&[]), None);
+
+        let f = fragments(7, &[0x45; 20], 17).unwrap();
+        assert_eq!(f.len(), 2);
+        assert_eq!(f[0].len(), 17);
+        assert_eq!(parse(&f[1]),
+                   Some(Message::Fragment {
+                       id: 7,
+                       index: 1,
+                       count: 2,
+                       data: &[0x45; 10],
+                   }));
+        assert_eq!(fragments(7, &[0; 300], 8), None);
+
+        assert_eq!(parse(&fec_data(1, 2, &[0x45])),
+                   Some(Message::FecData {
+                       group: 1,
+                       index: 2,
+                       data: &[0x45],
+                   }));
+        assert_eq!(parse(&fec_parity(1, 0, 3, &[9, 9])),
+                   Some(Message::FecParity {
+                       group: 1,
+                       index: 0,
+                       count: 3,
+                       shard: &[9, 9],
+                   }));
+    }
+
+    #[test]
+    fn compression() {
+        let p = vec![0x45; 1000];
+        let c = compress(&p).unwrap();
+        assert!(c.len() < 100);
+        match parse(&c) {
+            Some(Message::Compressed { len, data }) => assert_eq!(decompress(len, data), Some(p)),
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert_eq!(compress(&[0x45, 1, 2, 3, 4, 5, 6, 7]), None);
+        assert_eq!(decompress(10, &[0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn padding() {
+        let p = pad(&[0x45, 0, 0], 100);
+        assert_eq!(p.len(), 100);
+        assert_eq!(parse(&p), Some(Message::Padded(&[0x45, 0, 0])));
+        assert_eq!(pad(&[0x45, 0, 0], 4).len(), 6);
+        assert_eq!(parse(&[PADDED, 0, 10, 0x45]), None);
+        assert_eq!(parse(&pad(&dummy(), 100)), Some(Message::Padded(&[DUMMY])));
+    }
+}
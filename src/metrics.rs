@@ -0,0 +1,349 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+// A minimal HTTP server exporting the status of the tunnel in the
+// Prometheus text format at `/metrics`.
+
+use control::Status;
+use error::{Result, TiTunError};
+use futures::{Async, Future, Poll};
+use stats::{Histogram, SIZE_BUCKETS};
+use std::fmt::Write as FmtWrite;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{self, SocketAddr};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+
+/// Maximum number of connections being served. The oldest is closed when
+/// there are more.
+const MAX_CONNS: usize = 16;
+/// Maximum size of a request header.
+const MAX_REQUEST: usize = 8192;
+
+/// Render the status in the Prometheus text exposition format.
+pub fn render(status: &Status) -> String {
+    let mut out = String::new();
+    let tunnel = format!("tunnel=\"{}\"", escape(&status.tun));
+    let s = &status.stats;
+
+    {
+        let mut counter = |name: &str, help: &str, v: u64| {
+            let _ = writeln!(out, "# HELP titun_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE titun_{} counter", name);
+            let _ = writeln!(out, "titun_{}{{{}}} {}", name, tunnel, v);
+        };
+        counter("receive_packets_total",
+                "Authenticated packets received.",
+                s.rx_packets);
+        counter("receive_bytes_total",
+                "UDP payload bytes of authenticated packets received.",
+                s.rx_bytes);
+        counter("send_packets_total", "Packets sent.", s.tx_packets);
+        counter("send_bytes_total", "UDP payload bytes sent.", s.tx_bytes);
+        counter("decrypt_failures_total",
+                "Packets that failed authentication.",
+                s.decrypt_failures);
+        counter("timestamp_rejections_total",
+                "Authenticated packets rejected because the timestamp is too far off.",
+                s.timestamp_rejections);
+        counter("tun_write_errors_total",
+                "Errors writing to the tun device.",
+                s.tun_write_errors);
+        counter("send_errors_total", "Errors sending to the peer.", s.send_errors);
+        counter("too_big_drops_total",
+                "Packets dropped for exceeding the tunnel MTU.",
+                s.too_big);
+        counter("queue_drops_total",
+                "Packets dropped because the send queue is full.",
+                s.queue_drops);
+        counter("fec_recovered_packets_total",
+                "Packets recovered by forward error correction.",
+                s.fec_recovered);
+        counter("fec_lost_packets_total",
+                "Packets lost despite forward error correction.",
+                s.fec_lost);
+        counter("compressed_packets_total", "Packets compressed.", s.compressed);
+        counter("compression_saved_bytes_total",
+                "Bytes saved by compression.",
+                s.compress_saved);
+    }
+
+    let _ = writeln!(out,
+                     "# HELP titun_last_receive_timestamp_seconds Unix time of the last \
+                      authenticated packet received.");
+    let _ = writeln!(out, "# TYPE titun_last_receive_timestamp_seconds gauge");
+    if let Some(t) = status.last_rx {
+        let _ = writeln!(out, "titun_last_receive_timestamp_seconds{{{}}} {}", tunnel, t);
+    }
+    let _ = writeln!(out,
+                     "# HELP titun_last_send_timestamp_seconds Unix time of the last packet \
+                      sent.");
+    let _ = writeln!(out, "# TYPE titun_last_send_timestamp_seconds gauge");
+    if let Some(t) = status.last_tx {
+        let _ = writeln!(out, "titun_last_send_timestamp_seconds{{{}}} {}", tunnel, t);
+    }
+    let _ = writeln!(out, "# HELP titun_peer_info Current endpoint of the peer.");
+    let _ = writeln!(out, "# TYPE titun_peer_info gauge");
+    if let Some(ref p) = status.peer {
+        let _ = writeln!(out,
+                         "titun_peer_info{{{},endpoint=\"{}\"}} 1",
+                         tunnel,
+                         escape(p));
+    }
+
+    histogram(&mut out,
+              "receive_packet_size_bytes",
+              "Sizes of authenticated packets received, as UDP payload.",
+              &tunnel,
+              &s.rx_sizes,
+              s.rx_bytes);
+    histogram(&mut out,
+              "send_packet_size_bytes",
+              "Sizes of packets sent, as UDP payload.",
+              &tunnel,
+              &s.tx_sizes,
+              s.tx_bytes);
+    out
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, labels: &str, h: &Histogram, sum: u64) {
+    let _ = writeln!(out, "# HELP titun_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE titun_{} histogram", name);
+    let mut count = 0;
+    for (i, c) in h.counts.iter().enumerate() {
+        count += *c;
+        let le = SIZE_BUCKETS.get(i).map(|b| b.to_string()).unwrap_or_else(|| "+Inf".to_string());
+        let _ = writeln!(out, "titun_{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+    }
+    let _ = writeln!(out, "titun_{}_sum{{{}}} {}", name, labels, sum);
+    let _ = writeln!(out, "titun_{}_count{{{}}} {}", name, labels, count);
+}
+
+/// Escape a label value.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Build the response to a request, given its header.
+fn respond(request: &[u8], body: &str) -> Vec<u8> {
+    let line = request.split(|&b| b == b'\n').next().unwrap_or(&[]);
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", body)
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found.\n"),
+        _ => ("405 Method Not Allowed", "text/plain", "Method not allowed.\n"),
+    };
+    let mut response = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n",
+                               status,
+                               content_type,
+                               body.len())
+        .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    response
+}
+
+struct Conn {
+    stream: TcpStream,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    written: usize,
+}
+
+impl Conn {
+    /// Make progress on the connection. Returns `true` when it is done, or
+    /// has failed.
+    fn poll<F>(&mut self, status: &F) -> bool
+        where F: Fn() -> Status
+    {
+        if self.response.is_empty() {
+            let mut buf = [0u8; 1024];
+            loop {
+                match self.stream.read(&mut buf) {
+                    Ok(0) => return true,
+                    Ok(n) => self.request.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return false,
+                    Err(e) => {
+                        debug!("metrics: {}", e);
+                        return true;
+                    }
+                }
+                if header_complete(&self.request) {
+                    break;
+                }
+                if self.request.len() > MAX_REQUEST {
+                    return true;
+                }
+            }
+            self.response = respond(&self.request, &render(&status()));
+        }
+        while self.written < self.response.len() {
+            match self.stream.write(&self.response[self.written..]) {
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(e) => {
+                    debug!("metrics: {}", e);
+                    return true;
+                }
+            }
+        }
+        true
+    }
+}
+
+fn header_complete(request: &[u8]) -> bool {
+    request.windows(4).any(|w| w == b"\r\n\r\n") || request.windows(2).any(|w| w == b"\n\n")
+}
+
+pub struct MetricsServer<F> {
+    listener: TcpListener,
+    conns: Vec<Conn>,
+    status: F,
+}
+
+impl<F> MetricsServer<F>
+    where F: Fn() -> Status
+{
+    pub fn new(addr: &SocketAddr, status: F, handle: &Handle) -> Result<MetricsServer<F>> {
+        let listener = net::TcpListener::bind(addr)
+            .and_then(|l| TcpListener::from_listener(l, addr, handle))
+            .map_err(|e| TiTunError::network(format!("Failed to listen on {}", addr), e))?;
+        Ok(MetricsServer {
+            listener: listener,
+            conns: Vec::new(),
+            status: status,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+impl<F> Future for MetricsServer<F>
+    where F: Fn() -> Status
+{
+    type Item = ();
+    type Error = ::error::TiTunError;
+
+    fn poll(&mut self) -> Poll<(), ::error::TiTunError> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if self.conns.len() >= MAX_CONNS {
+                        self.conns.remove(0);
+                    }
+                    self.conns.push(Conn {
+                        stream: stream,
+                        request: Vec::new(),
+                        response: Vec::new(),
+                        written: 0,
+                    });
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("metrics: {}", e);
+                    break;
+                }
+            }
+        }
+        let status = &self.status;
+        let mut i = 0;
+        while i < self.conns.len() {
+            if self.conns[i].poll(status) {
+                self.conns.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use stats::Stats;
+    use std::net::TcpStream as StdTcpStream;
+    use std::thread;
+    use std::time::Duration;
+    use tokio_core::reactor::{Core, Timeout};
+
+    #[test]
+    fn metrics_endpoint() {
+        let mut stats = Stats::default();
+        stats.rx_packets = 2;
+        stats.rx_bytes = 1540;
+        stats.rx_sizes.observe(40);
+        stats.rx_sizes.observe(1500);
+        stats.decrypt_failures = 3;
+        let status = Status {
+            tun: "tun7".to_string(),
+            bind: vec!["0.0.0.0:5000".to_string()],
+            peer: Some("192.0.2.1:5000".to_string()),
+            last_rx: Some(1000),
+            last_tx: None,
+            stats: stats,
+        };
+
+        let text = render(&status);
+        assert!(text.contains("titun_receive_packets_total{tunnel=\"tun7\"} 2\n"));
+        assert!(text.contains("titun_decrypt_failures_total{tunnel=\"tun7\"} 3\n"));
+        assert!(text.contains("titun_last_receive_timestamp_seconds{tunnel=\"tun7\"} 1000\n"));
+        assert!(!text.contains("titun_last_send_timestamp_seconds{"));
+        assert!(text.contains("titun_peer_info{tunnel=\"tun7\",endpoint=\"192.0.2.1:5000\"} 1\n"));
+        assert!(text.contains("titun_receive_packet_size_bytes_bucket\
+                              {tunnel=\"tun7\",le=\"64\"} 1\n"));
+        assert!(text.contains("titun_receive_packet_size_bytes_bucket\
+                              {tunnel=\"tun7\",le=\"1280\"} 1\n"));
+        assert!(text.contains("titun_receive_packet_size_bytes_bucket\
+                              {tunnel=\"tun7\",le=\"1500\"} 2\n"));
+        assert!(text.contains("titun_receive_packet_size_bytes_bucket\
+                              {tunnel=\"tun7\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("titun_receive_packet_size_bytes_sum{tunnel=\"tun7\"} 1540\n"));
+        assert!(text.contains("titun_receive_packet_size_bytes_count{tunnel=\"tun7\"} 2\n"));
+
+        let mut core = Core::new().unwrap();
+        let s = status.clone();
+        let server = MetricsServer::new(&"127.0.0.1:0".parse().unwrap(),
+                                        move || s.clone(),
+                                        &core.handle())
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let get = |req: &[u8]| {
+                let mut s = StdTcpStream::connect(addr).unwrap();
+                s.write_all(req).unwrap();
+                let mut r = String::new();
+                s.read_to_string(&mut r).unwrap();
+                r
+            };
+            (get(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+             get(b"GET / HTTP/1.1\r\n\r\n"))
+        });
+        let timeout = Timeout::new(Duration::from_millis(500), &core.handle()).unwrap();
+        let _ = core.run(server.select(timeout.map_err(From::from)));
+        let (metrics, other) = client.join().unwrap();
+        assert!(metrics.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(metrics.ends_with(&text));
+        assert!(other.starts_with("HTTP/1.0 404 "));
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use crypto::OVERHEAD;
+
+const IPV4_HEADER: u32 = 20;
+const IPV6_HEADER: u32 = 40;
+const UDP_HEADER: u32 = 8;
+
+/// Minimum MTU of an IPv6 link.
+pub const MIN_MTU: u32 = 1280;
+
+/// Minimum MTU of an IPv4 link, as far as reassembly is concerned.
+const MIN_MTU_V4: u32 = 576;
+
+/// A probe is retransmitted this many times before it is considered lost.
+const MAX_PROBES: u32 = 3;
+
+/// Stop searching when the range is narrower than this.
+const SEARCH_STEP: u32 = 8;
+
+/// Bytes added to each tunneled packet by the outer IP and UDP headers and
+/// encryption.
+pub fn overhead(ipv6: bool) -> u32 {
+    let ip = if ipv6 { IPV6_HEADER } else { IPV4_HEADER };
+    ip + UDP_HEADER + OVERHEAD as u32
+}
+
+/// MTU of the tun device such that tunneled packets fit in `outer_mtu`.
+pub fn tun_mtu(outer_mtu: u32, ipv6: bool) -> u32 {
+    outer_mtu.saturating_sub(overhead(ipv6))
+}
+
+/// Packetization layer path MTU discovery (RFC 4821). The search is driven
+/// by calling `tick` periodically, with the largest probe size acknowledged
+/// by the peer since the last probe was sent.
+pub struct PmtuSearch {
+    base: u32,
+    max: u32,
+    /// Largest size known to work.
+    low: u32,
+    /// Largest size that may work.
+    high: u32,
+    /// Size of the outstanding probe and number of times it was sent.
+    probe: Option<(u32, u32)>,
+    /// Whether the outstanding probe is to confirm `low` still works.
+    confirming: bool,
+}
+
+impl PmtuSearch {
+    /// Search up to `max`.
+    pub fn new(max: u32, ipv6: bool) -> PmtuSearch {
+        let base = ::std::cmp::min(if ipv6 { MIN_MTU } else { MIN_MTU_V4 }, max);
+        PmtuSearch {
+            base: base,
+            max: max,
+            low: base,
+            high: max,
+            probe: None,
+            confirming: false,
+        }
+    }
+
+    /// Current estimate of the path MTU.
+    pub fn pmtu(&self) -> u32 {
+        self.low
+    }
+
+    pub fn done(&self) -> bool {
+        self.probe.is_none() && self.high - self.low < SEARCH_STEP
+    }
+
+    /// Search again, e.g. because the path may have changed. The current
+    /// estimate is confirmed first, then larger sizes are tried.
+    pub fn restart(&mut self) {
+        self.high = self.max;
+        self.probe = Some((self.low, 0));
+        self.confirming = true;
+    }
+
+    /// Returns the size of the probe to send now, if any.
+    pub fn tick(&mut self, acked: u32) -> Option<u32> {
+        if let Some((size, sent)) = self.probe {
+            if acked >= size {
+                self.low = size;
+                self.probe = None;
+                self.confirming = false;
+            } else if sent >= MAX_PROBES {
+                if self.confirming {
+                    // Black hole, start over from the base.
+                    self.low = self.base;
+                    self.confirming = false;
+                }
+                self.high = ::std::cmp::max(size - 1, self.low);
+                self.probe = None;
+            } else {
+                self.probe = Some((size, sent + 1));
+                return Some(size);
+            }
+        }
+        if self.done() {
+            return None;
+        }
+        let size = (self.low + self.high + 1) / 2;
+        self.probe = Some((size, 1));
+        Some(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_mtu() {
+        assert_eq!(tun_mtu(1500, false), 1432);
+        assert_eq!(tun_mtu(1500, true), 1412);
+        assert_eq!(tun_mtu(20, true), 0);
+    }
+
+    /// Run a search against a path with the given MTU.
+    fn search(s: &mut PmtuSearch, path_mtu: u32) {
+        let mut acked = 0;
+        for _ in 0..100 {
+            match s.tick(acked) {
+                Some(size) => acked = if size <= path_mtu { size } else { 0 },
+                None => return,
+            }
+        }
+        panic!("search does not finish");
+    }
+
+    #[test]
+    fn pmtu_search() {
+        let mut s = PmtuSearch::new(1500, false);
+        search(&mut s, 1400);
+        assert!(s.done());
+        assert!(s.pmtu() <= 1400 && s.pmtu() > 1400 - SEARCH_STEP);
+
+        // Path MTU decreases.
+        s.restart();
+        search(&mut s, 1300);
+        assert!(s.pmtu() <= 1300 && s.pmtu() > 1300 - SEARCH_STEP);
+
+        let mut s = PmtuSearch::new(1500, true);
+        search(&mut s, 1500);
+        assert!(s.pmtu() > 1500 - SEARCH_STEP);
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use byteorder::{ByteOrder, NativeEndian};
+use config::Config;
+use error::TiTunError;
+use nix::libc::{self, c_int, c_void, socklen_t};
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+
+const NLMSG_ERROR: u16 = 2;
+
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+
+const IFF_UP: u32 = 1;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_NOWHERE: u8 = 255;
+const RTN_UNICAST: u8 = 1;
+
+/// A rtnetlink socket, for configuring network interfaces without iproute2.
+pub struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Get index of a network interface.
+pub fn if_index(name: &str) -> Result<u32> {
+    let name = CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(Error::last_os_error()),
+        i => Ok(i),
+    }
+}
+
+fn family(a: &IpAddr) -> u8 {
+    match *a {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn addr_bytes(a: &IpAddr) -> Vec<u8> {
+    match *a {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+/// A netlink message under construction.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(ty: u16, flags: u16) -> Message {
+        let mut buf = vec![0u8; 16];
+        NativeEndian::write_u16(&mut buf[4..6], ty);
+        NativeEndian::write_u16(&mut buf[6..8], flags | NLM_F_REQUEST | NLM_F_ACK);
+        Message { buf: buf }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        let mut h = [0u8; 4];
+        NativeEndian::write_u16(&mut h[..2], (4 + data.len()) as u16);
+        NativeEndian::write_u16(&mut h[2..], ty);
+        self.push(&h);
+        self.push(data);
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        NativeEndian::write_u32(&mut self.buf[..4], len);
+        NativeEndian::write_u32(&mut self.buf[8..12], seq);
+        self.buf
+    }
+}
+
+impl Netlink {
+    pub fn new() -> Result<Netlink> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK,
+                         libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                         libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Netlink { fd: fd, seq: 0 })
+    }
+
+    /// Send a request and wait for the acknowledgement.
+    fn request(&mut self, m: Message) -> Result<()> {
+        self.seq += 1;
+        let m = m.finish(self.seq);
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let r = unsafe {
+            libc::sendto(self.fd,
+                         m.as_ptr() as *const c_void,
+                         m.len(),
+                         0,
+                         &addr as *const _ as *const libc::sockaddr,
+                         mem::size_of::<libc::sockaddr_nl>() as socklen_t)
+        };
+        if r < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let l = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+            if l < 0 {
+                return Err(Error::last_os_error());
+            }
+            let mut msgs = &buf[..l as usize];
+            while msgs.len() >= 16 {
+                let len = NativeEndian::read_u32(&msgs[..4]) as usize;
+                let ty = NativeEndian::read_u16(&msgs[4..6]);
+                let seq = NativeEndian::read_u32(&msgs[8..12]);
+                if len < 16 || len > msgs.len() {
+                    break;
+                }
+                if ty == NLMSG_ERROR && seq == self.seq && len >= 20 {
+                    let code = NativeEndian::read_i32(&msgs[16..20]);
+                    return if code == 0 {
+                        Ok(())
+                    } else {
+                        Err(Error::from_raw_os_error(-code as c_int))
+                    };
+                }
+                msgs = &msgs[::std::cmp::min((len + 3) & !3, msgs.len())..];
+            }
+        }
+    }
+
+    /// Set interface up or down.
+    pub fn set_link(&mut self, index: u32, up: bool) -> Result<()> {
+        let mut m = Message::new(RTM_NEWLINK, 0);
+        // struct ifinfomsg.
+        let mut ifi = [0u8; 16];
+        NativeEndian::write_u32(&mut ifi[4..8], index);
+        NativeEndian::write_u32(&mut ifi[8..12], if up { IFF_UP } else { 0 });
+        NativeEndian::write_u32(&mut ifi[12..16], IFF_UP);
+        m.push(&ifi);
+        self.request(m)
+    }
+
+    fn addr_message(ty: u16,
+                    flags: u16,
+                    index: u32,
+                    addr: &IpAddr,
+                    prefix: u8,
+                    peer: Option<&IpAddr>)
+                    -> Message {
+        let mut m = Message::new(ty, flags);
+        // struct ifaddrmsg.
+        let mut ifa = [0u8; 8];
+        ifa[0] = family(addr);
+        ifa[1] = prefix;
+        NativeEndian::write_u32(&mut ifa[4..8], index);
+        m.push(&ifa);
+        m.attr(IFA_LOCAL, &addr_bytes(addr));
+        m.attr(IFA_ADDRESS, &addr_bytes(peer.unwrap_or(addr)));
+        m
+    }
+
+    /// Add an address, optionally with a point-to-point peer address.
+    pub fn add_address(&mut self,
+                       index: u32,
+                       addr: &IpAddr,
+                       prefix: u8,
+                       peer: Option<&IpAddr>)
+                       -> Result<()> {
+        let m = Netlink::addr_message(RTM_NEWADDR,
+                                      NLM_F_CREATE | NLM_F_EXCL,
+                                      index,
+                                      addr,
+                                      prefix,
+                                      peer);
+        self.request(m)
+    }
+
+    pub fn del_address(&mut self,
+                       index: u32,
+                       addr: &IpAddr,
+                       prefix: u8,
+                       peer: Option<&IpAddr>)
+                       -> Result<()> {
+        let m = Netlink::addr_message(RTM_DELADDR, 0, index, addr, prefix, peer);
+        self.request(m)
+    }
+
+    fn route_message(ty: u16, flags: u16, index: u32, dst: &IpAddr, prefix: u8) -> Message {
+        let mut m = Message::new(ty, flags);
+        // struct rtmsg.
+        let rtm = [family(dst),
+                   prefix,
+                   0,
+                   0,
+                   RT_TABLE_MAIN,
+                   RTPROT_BOOT,
+                   if ty == RTM_DELROUTE { RT_SCOPE_NOWHERE } else { RT_SCOPE_LINK },
+                   RTN_UNICAST,
+                   0,
+                   0,
+                   0,
+                   0];
+        m.push(&rtm);
+        m.attr(RTA_DST, &addr_bytes(dst));
+        let mut oif = [0u8; 4];
+        NativeEndian::write_u32(&mut oif, index);
+        m.attr(RTA_OIF, &oif);
+        m
+    }
+
+    /// Add a route to `dst/prefix` via the interface.
+    pub fn add_route(&mut self, index: u32, dst: &IpAddr, prefix: u8) -> Result<()> {
+        let m = Netlink::route_message(RTM_NEWROUTE,
+                                       NLM_F_CREATE | NLM_F_EXCL,
+                                       index,
+                                       dst,
+                                       prefix);
+        self.request(m)
+    }
+
+    pub fn del_route(&mut self, index: u32, dst: &IpAddr, prefix: u8) -> Result<()> {
+        let m = Netlink::route_message(RTM_DELROUTE, 0, index, dst, prefix);
+        self.request(m)
+    }
+}
+
+fn with_context<T>(r: Result<T>, what: String) -> ::error::Result<T> {
+    r.map_err(|e| TiTunError::device(what, e))
+}
+
+/// Apply `address`, `peer_address`, `up` and `routes` from the config to the
+/// interface.
+pub fn configure_interface(name: &str, c: &Config) -> ::error::Result<()> {
+    if c.address.is_none() && !c.up && c.routes.is_empty() {
+        return Ok(());
+    }
+    let index = with_context(if_index(name), format!("Failed to get index of {}", name))?;
+    let mut nl = with_context(Netlink::new(), "Failed to open netlink socket".to_string())?;
+
+    if let Some((ref addr, prefix)) = c.address {
+        with_context(nl.add_address(index, addr, prefix, c.peer_address.as_ref()),
+                     format!("Failed to add address {}/{} to {}", addr, prefix, name))?;
+    }
+    if c.up {
+        with_context(nl.set_link(index, true),
+                     format!("Failed to set link {} up", name))?;
+    }
+    for &(ref dst, prefix) in &c.routes {
+        with_context(nl.add_route(index, dst, prefix),
+                     format!("Failed to add route {}/{} via {}", dst, prefix, name))?;
+    }
+    Ok(())
+}
+
+/// Remove routes and address added by `configure_interface`. Errors are
+/// logged and otherwise ignored.
+pub fn deconfigure_interface(name: &str, c: &Config) {
+    if c.address.is_none() && c.routes.is_empty() {
+        return;
+    }
+    let r = if_index(name).and_then(|index| Netlink::new().map(|nl| (index, nl)));
+    let (index, mut nl) = match r {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to deconfigure {}: {}", name, e);
+            return;
+        }
+    };
+    for &(ref dst, prefix) in &c.routes {
+        if let Err(e) = nl.del_route(index, dst, prefix) {
+            warn!("Failed to delete route {}/{}: {}", dst, prefix, e);
+        }
+    }
+    if let Some((ref addr, prefix)) = c.address {
+        if let Err(e) = nl.del_address(index, addr, prefix, c.peer_address.as_ref()) {
+            warn!("Failed to delete address {}/{}: {}", addr, prefix, e);
+        }
+    }
+}
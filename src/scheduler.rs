@@ -0,0 +1,294 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use ip;
+use std::collections::VecDeque;
+
+/// Number of flow queues in a fair-queued band.
+const FLOWS: usize = 64;
+
+/// Deficit round robin quantum, in bytes.
+const QUANTUM: usize = 1514;
+
+/// Packets no longer than this are considered interactive.
+const SMALL_PACKET: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Band {
+    /// SSH keystrokes, VoIP, TCP ACKs, etc. Always sent first.
+    Priority,
+    /// Everything else. Flows share bandwidth fairly.
+    Normal,
+    /// Traffic marked as low priority (DSCP CS1). Sent when nothing else is queued.
+    Bulk,
+}
+
+struct Flow {
+    packets: VecDeque<Vec<u8>>,
+    bytes: usize,
+    deficit: usize,
+    active: bool,
+}
+
+/// Deficit round robin over a fixed number of hashed flow queues.
+struct FairQueue {
+    flows: Vec<Flow>,
+    active: VecDeque<usize>,
+}
+
+impl FairQueue {
+    fn new(n: usize) -> FairQueue {
+        FairQueue {
+            flows: (0..n)
+                .map(|_| {
+                    Flow {
+                        packets: VecDeque::new(),
+                        bytes: 0,
+                        deficit: 0,
+                        active: false,
+                    }
+                })
+                .collect(),
+            active: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, flow: usize, p: Vec<u8>) {
+        let flow = flow % self.flows.len();
+        let f = &mut self.flows[flow];
+        f.bytes += p.len();
+        f.packets.push_back(p);
+        if !f.active {
+            f.active = true;
+            f.deficit = QUANTUM;
+            self.active.push_back(flow);
+        }
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        while let Some(i) = self.active.pop_front() {
+            let f = &mut self.flows[i];
+            let len = match f.packets.front() {
+                Some(p) => p.len(),
+                None => {
+                    f.active = false;
+                    continue;
+                }
+            };
+            if f.deficit < len {
+                f.deficit += QUANTUM;
+                self.active.push_back(i);
+                continue;
+            }
+            f.deficit -= len;
+            f.bytes -= len;
+            let p = f.packets.pop_front();
+            if f.packets.is_empty() {
+                f.active = false;
+            } else {
+                self.active.push_front(i);
+            }
+            return p;
+        }
+        None
+    }
+
+    /// Drop the head packet of the flow with the most queued bytes.
+    fn drop_fattest(&mut self) -> bool {
+        let fattest = self.flows
+            .iter_mut()
+            .filter(|f| f.bytes > 0)
+            .max_by_key(|f| f.bytes);
+        if let Some(f) = fattest {
+            let p = f.packets.pop_front().unwrap();
+            f.bytes -= p.len();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Queues packets read from the tun device and decides which one to send next.
+///
+/// Interactive packets are sent with strict priority, other traffic is fair
+/// queued per flow, somewhat like fq_codel. When the queue is full, packets
+/// are dropped from the fattest flow. If disabled, this is just a FIFO.
+pub struct Scheduler {
+    enabled: bool,
+    priority_ports: Vec<(u16, u16)>,
+    limit: usize,
+    len: usize,
+    priority: VecDeque<Vec<u8>>,
+    normal: FairQueue,
+    bulk: FairQueue,
+}
+
+impl Scheduler {
+    pub fn new(enabled: bool, priority_ports: Vec<(u16, u16)>, limit: usize) -> Scheduler {
+        Scheduler {
+            enabled: enabled,
+            priority_ports: priority_ports,
+            limit: limit,
+            len: 0,
+            priority: VecDeque::new(),
+            normal: FairQueue::new(if enabled { FLOWS } else { 1 }),
+            bulk: FairQueue::new(FLOWS),
+        }
+    }
+
+    pub fn classify(&self, p: &[u8]) -> Band {
+        if !self.enabled {
+            return Band::Normal;
+        }
+        match ip::dscp(p) {
+            // EF, CS4-CS7, AF4x.
+            Some(46) | Some(32) | Some(40) | Some(48) | Some(56) | Some(34) | Some(36) |
+            Some(38) => return Band::Priority,
+            // CS1, a.k.a. scavenger.
+            Some(8) => return Band::Bulk,
+            _ => {}
+        }
+        if p.len() <= SMALL_PACKET || ip::is_pure_tcp_ack(p) {
+            return Band::Priority;
+        }
+        if let Some((s, d)) = ip::ports(p) {
+            let in_range = |x| self.priority_ports.iter().any(|&(lo, hi)| lo <= x && x <= hi);
+            if in_range(s) || in_range(d) {
+                return Band::Priority;
+            }
+        }
+        Band::Normal
+    }
+
+    /// Queue a packet. Returns false if some packet had to be dropped.
+    pub fn enqueue(&mut self, p: Vec<u8>) -> bool {
+        let mut band = self.classify(&p);
+        // Do not let the priority band take over the whole queue.
+        if band == Band::Priority && self.priority.len() >= self.limit / 4 {
+            band = Band::Normal;
+        }
+        let mut dropped = false;
+        if self.len >= self.limit {
+            dropped = true;
+            if self.bulk.drop_fattest() || self.normal.drop_fattest() {
+                self.len -= 1;
+            } else {
+                return false;
+            }
+        }
+        self.len += 1;
+        match band {
+            Band::Priority => self.priority.push_back(p),
+            Band::Normal => {
+                let h = if self.enabled { flow_hash(&p) } else { 0 };
+                self.normal.push(h, p);
+            }
+            Band::Bulk => {
+                let h = flow_hash(&p);
+                self.bulk.push(h, p);
+            }
+        }
+        !dropped
+    }
+
+    /// Get the next packet to send.
+    pub fn dequeue(&mut self) -> Option<Vec<u8>> {
+        let p = self.priority
+            .pop_front()
+            .or_else(|| self.normal.pop())
+            .or_else(|| self.bulk.pop());
+        if p.is_some() {
+            self.len -= 1;
+        }
+        p
+    }
+}
+
+/// FNV-1a over addresses, protocol and ports.
+fn flow_hash(p: &[u8]) -> usize {
+    let mut h: u32 = 0x811c9dc5;
+    {
+        let mut feed = |bytes: &[u8]| for b in bytes {
+            h ^= *b as u32;
+            h = h.wrapping_mul(0x01000193);
+        };
+        if let Some((src, dst)) = ip::addresses(p) {
+            feed(src);
+            feed(dst);
+        }
+        if let Some((proto, _)) = ip::transport(p) {
+            feed(&[proto]);
+        }
+        if let Some((s, d)) = ip::ports(p) {
+            feed(&[(s >> 8) as u8, s as u8, (d >> 8) as u8, d as u8]);
+        }
+    }
+    h as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ip::tests::{tcp4, udp6};
+
+    #[test]
+    fn classification() {
+        let s = Scheduler::new(true, vec![(5000, 5100)], 100);
+        assert_eq!(s.classify(&tcp4(0, 40000, 80, 0x10, 0)), Band::Priority);
+        assert_eq!(s.classify(&tcp4(0, 40000, 80, 0x18, 1000)), Band::Normal);
+        assert_eq!(s.classify(&tcp4(0xb8, 40000, 80, 0x18, 1000)), Band::Priority);
+        assert_eq!(s.classify(&tcp4(0x20, 40000, 80, 0x18, 1000)), Band::Bulk);
+        assert_eq!(s.classify(&udp6(0, 5060, 40000, 1000)), Band::Priority);
+        assert_eq!(s.classify(&udp6(0, 6000, 40000, 1000)), Band::Normal);
+
+        let s = Scheduler::new(false, vec![(5000, 5100)], 100);
+        assert_eq!(s.classify(&tcp4(0xb8, 40000, 80, 0x10, 0)), Band::Normal);
+    }
+
+    #[test]
+    fn priority_and_fairness() {
+        let mut s = Scheduler::new(true, vec![], 100);
+        for _ in 0..10 {
+            assert!(s.enqueue(tcp4(0, 1000, 80, 0x18, 1400)));
+        }
+        assert!(s.enqueue(udp6(0, 2000, 80, 1400)));
+        assert!(s.enqueue(tcp4(0, 22, 40000, 0x18, 10)));
+
+        // Interactive packet first.
+        assert_eq!(ip::ports(&s.dequeue().unwrap()), Some((22, 40000)));
+        // Then the two bulk flows share bandwidth.
+        let first_two: Vec<_> = (0..2).map(|_| ip::ports(&s.dequeue().unwrap())).collect();
+        assert!(first_two.contains(&Some((2000, 80))));
+        while s.dequeue().is_some() {}
+        assert_eq!(s.len, 0);
+    }
+
+    #[test]
+    fn fifo_when_disabled() {
+        let mut s = Scheduler::new(false, vec![], 3);
+        for i in 0..3 {
+            assert!(s.enqueue(tcp4(0, i, 80, 0x18, 1000)));
+        }
+        // Full, oldest packet is dropped.
+        assert!(!s.enqueue(tcp4(0, 3, 80, 0x10, 0)));
+        for i in 1..4 {
+            assert_eq!(ip::ports(&s.dequeue().unwrap()), Some((i, 80)));
+        }
+        assert_eq!(s.dequeue(), None);
+    }
+}
@@ -48,4 +48,19 @@ impl ScriptRunner {
             Err(Error::new(ErrorKind::Other, "running config script failed"))
         }
     }
+
+    /// Check the syntax of the script without running it, with `sh -n`.
+    pub fn check<R>(mut self, mut r: R) -> Result<()>
+        where R: Read
+    {
+        let mut p = self.c.arg("-n").stdin(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        copy(&mut r, p.stdin.as_mut().unwrap())?;
+        let out = p.wait_with_output()?;
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::Other,
+                           String::from_utf8_lossy(&out.stderr).trim().to_string()))
+        }
+    }
 }
@@ -0,0 +1,100 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+/// Traffic and error counters of a tunnel.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Authenticated packets received, and their size as UDP payload.
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Packets sent, and their size as UDP payload.
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Packets that failed authentication.
+    pub decrypt_failures: u64,
+    /// Authenticated packets rejected because the timestamp is too far off,
+    /// e.g. replayed packets.
+    pub timestamp_rejections: u64,
+    pub tun_write_errors: u64,
+    pub send_errors: u64,
+    /// Packets dropped for exceeding the tunnel MTU.
+    pub too_big: u64,
+    /// Packets dropped because the send queue is full.
+    pub queue_drops: u64,
+    pub fec_recovered: u64,
+    pub fec_lost: u64,
+    /// Packets compressed, and bytes saved by that.
+    pub compressed: u64,
+    pub compress_saved: u64,
+    /// Sizes of packets received and sent.
+    pub rx_sizes: Histogram,
+    pub tx_sizes: Histogram,
+}
+
+/// Upper bounds of the buckets of packet size histograms, in bytes of UDP
+/// payload. Larger packets go to an extra last bucket.
+pub const SIZE_BUCKETS: [usize; 8] = [64, 128, 256, 512, 1024, 1280, 1500, 9000];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Number of packets in each bucket, not cumulative.
+    pub counts: [u64; 9],
+}
+
+impl Histogram {
+    pub fn observe(&mut self, size: usize) {
+        let i = SIZE_BUCKETS.iter().position(|&b| size <= b).unwrap_or(SIZE_BUCKETS.len());
+        self.counts[i] += 1;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "  received: {} packets, {} bytes",
+                 self.rx_packets,
+                 self.rx_bytes)?;
+        writeln!(f, "  sent: {} packets, {} bytes", self.tx_packets, self.tx_bytes)?;
+        writeln!(f,
+                 "  rejected: {} decryption failures, {} timestamp rejections",
+                 self.decrypt_failures,
+                 self.timestamp_rejections)?;
+        writeln!(f,
+                 "  errors: {} tun write errors, {} send errors",
+                 self.tun_write_errors,
+                 self.send_errors)?;
+        write!(f,
+               "  dropped: {} too big, {} queue full",
+               self.too_big,
+               self.queue_drops)?;
+        if self.fec_recovered != 0 || self.fec_lost != 0 {
+            write!(f,
+                   "\n  fec: {} recovered, {} lost",
+                   self.fec_recovered,
+                   self.fec_lost)?;
+        }
+        if self.compressed != 0 {
+            write!(f,
+                   "\n  compression: {} packets, {} bytes saved",
+                   self.compressed,
+                   self.compress_saved)?;
+        }
+        Ok(())
+    }
+}
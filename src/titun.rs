@@ -15,45 +15,106 @@
 // You should have received a copy of the GNU General Public License
 // along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
 
-use config::Config;
-use crypto::Crypto;
+use byteorder::{BigEndian, ByteOrder};
+use config::{Config, MssClamp, Padding};
+use control::{self, ControlServer, Status, unix_time};
+use crypto::{Crypto, DecryptError, OVERHEAD};
 use error::{Result, TiTunError};
+use fec;
+use fragment::Reassembler;
+use framing::Framer;
 use futures::{Async, Future, Poll, Stream};
+use futures::future::{self, select_all};
+use futures::sync::oneshot;
 use futures::task;
+use ip;
+use message::{self, Message};
+use metrics::MetricsServer;
+use mtu::{self, MIN_MTU, PmtuSearch, tun_mtu};
+use netlink::{configure_interface, deconfigure_interface};
+use nix::libc;
+use scheduler::Scheduler;
 use script_runner::ScriptRunner;
-use std::cell::RefCell;
+use sodiumoxide::randombytes::randombytes_into;
+use stats::Stats;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::convert::From;
-use std::io::{Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::SocketAddr;
 use std::ops::DerefMut;
+use std::path::Path;
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use systemd::notify_ready;
-use tokio_core::net::UdpSocket;
-use tokio_core::reactor::{Core, PollEvented};
+use tokio_core::reactor::{Core, Handle, Interval, PollEvented};
 use tokio_signal;
 use tun::Tun;
+use udp::UdpSocket;
 
 /// Run titun with some configuration. Will not return unless an error happens.
-pub fn run(config: &Config) -> Result<()> {
+///
+/// On SIGHUP, the config is re-read from `config_file`, and changes that can
+/// be applied to the running tunnel are applied.
+pub fn run(config: &Config, config_file: Option<&Path>) -> Result<()> {
     let mut core = Core::new()?;
     let handle = core.handle();
 
-    assert!(config.peer.is_some() || config.bind.is_some());
+    assert!(config.peer.is_some() || !config.bind.is_empty());
 
-    let bind = config.bind.unwrap_or_else(|| "0.0.0.0:0".parse().unwrap());
-    let sock = UdpSocket::bind(&bind, &handle)?;
-    info!("Bind to {}.", sock.local_addr()?);
+    let binds = if config.bind.is_empty() {
+        vec!["0.0.0.0:0".parse().unwrap()]
+    } else {
+        config.bind.clone()
+    };
+    let mut socks = Vec::with_capacity(binds.len());
+    for b in &binds {
+        let sock = bind_socket(b, config, &handle)?;
+        info!("Bind to {}.", sock.local_addr()?);
+        socks.push(sock);
+    }
+    let bind = binds[0];
+    // Send to a fixed peer from the first socket of the right address family.
+    let reply_sock = config.peer
+        .and_then(|p| binds.iter().position(|b| b.is_ipv6() == p.is_ipv6()))
+        .unwrap_or(0);
 
-    let tun = Tun::create(config.dev_name.as_ref().map(|n| n.as_str()))?;
+    let tun = Tun::create(config.dev_name.as_ref().map(|n| n.as_str()))
+        .map_err(|e| TiTunError::device("Failed to create tun device", e))?;
     let tun_name = tun.get_name().to_string();
     info!("Tun device created: {}.", &tun_name);
-    tun.set_nonblocking(true)?;
+    tun.set_nonblocking(true)
+        .map_err(|e| TiTunError::device("Failed to set tun device non-blocking", e))?;
+
+    let padding = config.packet_padding();
+    let overhead = config.message_overhead();
+    let framer = Framer::new(config.framing);
+    let framing_overhead = config.framing_overhead();
+    // Largest packet that fits in a cover traffic packet.
+    let cover_mtu = config.cover_mtu();
+    let mtu = config.tun_mtu();
+    tun.set_mtu(mtu)
+        .map_err(|e| TiTunError::device(format!("Failed to set MTU of {}", tun_name), e))?;
+    info!("MTU of {} set to {}.", &tun_name, mtu);
+    if mtu < MIN_MTU {
+        warn!("MTU {} is less than {}, IPv6 will not work over the tunnel.", mtu, MIN_MTU);
+    }
+    if config.bufsize < mtu as usize + OVERHEAD {
+        warn!("bufsize {} is too small for MTU {}, should be at least {}.",
+              config.bufsize,
+              mtu,
+              mtu as usize + OVERHEAD);
+    }
+
+    configure_interface(&tun_name, config)?;
 
     if let Some(ref on_up) = config.on_up {
         ScriptRunner::new().env("TUN", &tun_name).run(on_up.as_bytes())?;
     }
 
-    let tun = PollEvented::new(tun, &handle)?;
+    let tun = PollEvented::new(tun, &handle)
+        .map_err(|e| TiTunError::device("Failed to register tun device", e))?;
 
     // If peer is set, we send packets to it. Otherwise we send to who ever
     // most recently send us an authenticated packet.
@@ -69,25 +130,193 @@ pub fn run(config: &Config) -> Result<()> {
 
     let common = Rc::new(RefCell::new(Common {
         crypto: crypto,
-        sock: sock,
+        framer: framer,
+        socks: socks,
+        reply_sock: reply_sock,
+        unanswered_since: Cell::new(None),
+        last_rx: None,
+        last_tx: Cell::new(None),
+        stats: RefCell::new(Stats::default()),
         tun: tun,
         buf: vec![0u8; config.bufsize],
     }));
 
+    // Config with the changes applied on reload.
+    let live = Rc::new(RefCell::new(config.clone()));
+
+    let pmtu_acked = Rc::new(Cell::new(0));
+    // Largest packet that can be sent through the tunnel.
+    let effective_mtu = Rc::new(Cell::new(config.effective_mtu()));
+
     let sock_to_tun = SockToTun {
         common: common.clone(),
         remote_addr: remote_addr1,
-        buf_to_write: None,
+        ecn: config.ecn,
+        pmtu_acked: pmtu_acked.clone(),
+        mss_clamp: config.mss_clamp,
+        mtu: effective_mtu.clone(),
+        reassembler: Reassembler::new(REASSEMBLY_LIMIT, Duration::from_secs(REASSEMBLY_TIMEOUT)),
+        fec: fec::Decoder::new(),
+        to_write: VecDeque::new(),
+        next_sock: 0,
     };
 
+    let to_send = Rc::new(RefCell::new(VecDeque::new()));
+
     let tun_to_sock = TunToSock {
-        common: common,
-        remote_addr: remote_addr,
-        buf_to_send: None,
+        mss_clamp: config.mss_clamp,
+        common: common.clone(),
+        remote_addr: remote_addr.clone(),
+        scheduler: Scheduler::new(config.scheduler,
+                                  config.priority_ports.clone(),
+                                  config.queue_limit),
+        copy_dscp: config.copy_dscp,
+        ecn: config.ecn,
+        mtu: effective_mtu.clone(),
+        fragment: config.fragment,
+        fragment_id: 0,
+        fec: config.fec.map(|(count, parity)| fec::Encoder::new(count, parity)),
+        compress: config.compress,
+        padding: padding.clone(),
+        overhead: overhead as usize,
+        cover: config.cover_rate.is_some(),
+        to_send: to_send.clone(),
     };
 
-    let titun_fut = sock_to_tun.select(tun_to_sock).then(|r| match r {
-        Err((e, _)) => Err(e),
+    let mut futures: Vec<Box<Future<Item = (), Error = TiTunError>>> =
+        vec![Box::new(sock_to_tun), Box::new(tun_to_sock)];
+
+    if config.pmtud {
+        futures.push(Box::new(PmtuDiscovery {
+            common: common.clone(),
+            remote_addr: remote_addr.clone(),
+            acked: pmtu_acked,
+            interval: Interval::new(Duration::from_secs(1), &handle)?,
+            peer: None,
+            search: PmtuSearch::new(config.outer_mtu, false),
+            max: config.outer_mtu,
+            idle_ticks: 0,
+            reported: None,
+            // When fragmenting, keep the tun MTU and fragment packets that
+            // do not fit in the path MTU instead.
+            set_mtu: config.mtu.is_none() && !config.fragment,
+            tun_mtu: mtu,
+            overhead: overhead + framing_overhead,
+            effective_mtu: effective_mtu.clone(),
+            config: live.clone(),
+            tun_name: tun_name.clone(),
+        }));
+    }
+
+    if config.rotate_port.is_some() || config.rotate_port_idle.is_some() {
+        let mut addr = bind;
+        addr.set_port(0);
+        futures.push(Box::new(PortRotation {
+            common: common.clone(),
+            handle: handle.clone(),
+            addr: addr,
+            config: config.clone(),
+            interval: Interval::new(Duration::from_secs(1), &handle)?,
+            last_rotation: Instant::now(),
+        }));
+    }
+
+    {
+        let common = common.clone();
+        let remote_addr = remote_addr.clone();
+        let name = tun_name.clone();
+        let status = Rc::new(move || {
+            let common = common.borrow();
+            let stats = common.stats.borrow().clone();
+            Status {
+                tun: name.clone(),
+                bind: common.socks
+                    .iter()
+                    .filter_map(|s| s.local_addr().ok())
+                    .map(|a| a.to_string())
+                    .collect(),
+                peer: remote_addr.borrow().map(|a| a.to_string()),
+                last_rx: common.last_rx.map(unix_time),
+                last_tx: common.last_tx.get().map(unix_time),
+                stats: stats,
+            }
+        });
+        let path = control::socket_path(&tun_name);
+        let s = status.clone();
+        match ControlServer::new(path, move || s(), &handle) {
+            Ok(server) => futures.push(Box::new(server)),
+            Err(e) => warn!("Failed to create control socket: {}", e),
+        }
+        if let Some(addr) = config.metrics_listen {
+            let server = MetricsServer::new(&addr, move || status(), &handle)?;
+            info!("Serving metrics on http://{}/metrics.", addr);
+            futures.push(Box::new(server));
+        }
+    }
+
+    // Cover traffic never stops, so keepalives are not needed.
+    if config.cover_rate.is_none() {
+        futures.push(Box::new(KeepAlive {
+            common: common.clone(),
+            remote_addr: remote_addr.clone(),
+            config: live.clone(),
+            padding: padding.clone(),
+            mtu: effective_mtu.clone(),
+            overhead: overhead as usize,
+            interval: Interval::new(Duration::from_secs(1), &handle)?,
+        }));
+    }
+
+    {
+        let sighup = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP, &handle);
+        let sighup = core.run(sighup)?;
+        let config_file = config_file.map(|p| p.to_path_buf());
+        let live = live.clone();
+        let common = common.clone();
+        let remote_addr = remote_addr.clone();
+        futures.push(Box::new(sighup.map_err(From::from).for_each(move |_| {
+            let path = match config_file {
+                Some(ref path) => path.clone(),
+                None => {
+                    warn!("Received SIGHUP, but there is no config file to reload.");
+                    return Box::new(future::ok(())) as Box<Future<Item = (), Error = TiTunError>>;
+                }
+            };
+            info!("Received SIGHUP, reloading {}.", path.display());
+            // Loading may take a while, to derive a key from a passphrase or
+            // to run `key_command`, so do it off the reactor thread.
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || tx.complete(Config::load(&path)));
+            let live = live.clone();
+            let common = common.clone();
+            let remote_addr = remote_addr.clone();
+            Box::new(rx.then(move |r| {
+                match r {
+                    Ok(Ok(new)) => reload(new, &live, &common, &remote_addr),
+                    Ok(Err(e)) => warn!("Failed to reload config, keeping the current one: {}", e),
+                    Err(_) => warn!("Failed to reload config, keeping the current one."),
+                }
+                Ok(())
+            }))
+        })));
+    }
+
+    if let Some(rate) = config.cover_rate {
+        let period = ::std::cmp::max(1_000_000_000 / rate as u64, 1_000_000);
+        futures.push(Box::new(CoverTraffic {
+            common: common,
+            remote_addr: remote_addr,
+            queue: to_send,
+            interval: Interval::new(Duration::new(0, period as u32), &handle)?,
+            start: Instant::now(),
+            rate: rate as u64,
+            sent: 0,
+            size: (cover_mtu.unwrap() + overhead) as usize,
+        }));
+    }
+
+    let titun_fut = select_all(futures).then(|r| match r {
+        Err((e, _, _)) => Err(e),
         Ok(_) => unreachable!(),
     });
 
@@ -98,9 +327,10 @@ pub fn run(config: &Config) -> Result<()> {
 
     let signal_fut = sigint.select(sigterm).map_err(From::from).for_each(|s| {
         info!("Received signal {}, exiting.", s);
-        if let Some(ref on_down) = config.on_down {
+        if let Some(ref on_down) = live.borrow().on_down {
             ScriptRunner::new().env("TUN", &tun_name).run(on_down.as_bytes())?;
         }
+        deconfigure_interface(&tun_name, config);
         Err(TiTunError::GracefulExit)
     });
 
@@ -113,17 +343,239 @@ pub fn run(config: &Config) -> Result<()> {
     }))
 }
 
+/// Apply the changes in a re-read config that can be applied to the running
+/// tunnel. Other changes are reported and ignored.
+fn reload(new: Config,
+          live: &RefCell<Config>,
+          common: &RefCell<Common>,
+          remote_addr: &RefCell<Option<SocketAddr>>) {
+    let mut live = live.borrow_mut();
+    let restart = live.restart_required(&new);
+    for o in &restart {
+        warn!("`{}` changed, restart to apply.", o);
+    }
+
+    let mut common = common.borrow_mut();
+    if new.key != live.key || new.max_diff != live.max_diff {
+        common.crypto = Crypto::new(new.key.clone(), new.max_diff);
+        info!("Key and max_diff updated.");
+    }
+    if new.peer != live.peer && !restart.contains(&"peer") {
+        let peer = new.peer.unwrap();
+        *remote_addr.borrow_mut() = Some(peer);
+        if let Some(i) = common.socks
+            .iter()
+            .position(|s| s.local_addr().map(|a| a.is_ipv6() == peer.is_ipv6()).unwrap_or(false)) {
+            common.reply_sock = i;
+        }
+        common.unanswered_since.set(None);
+        info!("Peer changed to {}.", peer);
+        live.peer = new.peer;
+    }
+    live.key = new.key;
+    live.max_diff = new.max_diff;
+    live.keepalive = new.keepalive;
+    live.on_up = new.on_up;
+    live.on_down = new.on_down;
+    live.on_pmtu_change = new.on_pmtu_change;
+}
+
+/// Restart path MTU discovery every this many ticks.
+const PMTU_REPROBE_TICKS: u32 = 600;
+
+/// Discard incomplete fragmented packets after this many seconds.
+const REASSEMBLY_TIMEOUT: u64 = 3;
+
+/// Maximum number of bytes buffered for reassembly.
+const REASSEMBLY_LIMIT: usize = 1 << 20;
+
+/// With cover traffic, packets are taken from the scheduler when fewer than
+/// this many are waiting for a slot.
+const COVER_QUEUE: usize = 8;
+
+/// Bind a UDP socket and set options according to the config.
+fn bind_socket(addr: &SocketAddr, config: &Config, handle: &Handle) -> Result<UdpSocket> {
+    let sock = UdpSocket::bind(addr, handle)
+        .map_err(|e| TiTunError::network(format!("Failed to bind to {}", addr), e))?;
+    if config.ecn {
+        sock.set_recv_tos(true).map_err(|e| TiTunError::network("Failed to enable ECN", e))?;
+    }
+    if config.pmtud {
+        sock.set_pmtu_probe()
+            .map_err(|e| TiTunError::network("Failed to enable path MTU discovery", e))?;
+    }
+    Ok(sock)
+}
+
 struct Common {
     crypto: Crypto,
-    sock: UdpSocket,
+    framer: Framer,
+    socks: Vec<UdpSocket>,
+    /// Index of the socket to send from: the one the peer last used.
+    reply_sock: usize,
+    /// When the first packet sent since the last one received was sent.
+    unanswered_since: Cell<Option<Instant>>,
+    last_rx: Option<SystemTime>,
+    last_tx: Cell<Option<SystemTime>>,
+    stats: RefCell<Stats>,
     tun: PollEvented<Tun>,
     buf: Vec<u8>,
 }
 
+impl Common {
+    /// Encrypt a message and add the outer framing.
+    fn encrypt(&self, m: &[u8]) -> Vec<u8> {
+        self.framer.wrap(self.crypto.encrypt(m))
+    }
+
+    fn decrypt(&self, p: &[u8]) -> ::std::result::Result<Vec<u8>, DecryptError> {
+        match self.framer.unwrap(p) {
+            Some(c) => self.crypto.decrypt(c),
+            None => Err(DecryptError::Invalid),
+        }
+    }
+
+    fn send_to(&self, p: &[u8], addr: &SocketAddr, tos: Option<u8>) -> io::Result<usize> {
+        let r = match tos {
+            Some(tos) => self.socks[self.reply_sock].send_to_with_tos(p, addr, tos),
+            None => self.socks[self.reply_sock].send_to(p, addr),
+        };
+        match r {
+            Ok(_) => {
+                let mut stats = self.stats.borrow_mut();
+                stats.tx_packets += 1;
+                stats.tx_bytes += p.len() as u64;
+                stats.tx_sizes.observe(p.len());
+            }
+            Err(ref e) if e.kind() != ErrorKind::WouldBlock => {
+                self.stats.borrow_mut().send_errors += 1;
+            }
+            _ => {}
+        }
+        if r.is_ok() {
+            self.last_tx.set(Some(SystemTime::now()));
+            if self.unanswered_since.get().is_none() {
+                self.unanswered_since.set(Some(Instant::now()));
+            }
+        }
+        r
+    }
+}
+
 struct SockToTun {
     common: Rc<RefCell<Common>>,
     remote_addr: Option<Rc<RefCell<Option<SocketAddr>>>>,
-    buf_to_write: Option<Vec<u8>>,
+    ecn: bool,
+    /// Largest path MTU probe acknowledged by the peer.
+    pmtu_acked: Rc<Cell<u32>>,
+    mss_clamp: Option<MssClamp>,
+    mtu: Rc<Cell<u32>>,
+    reassembler: Reassembler,
+    fec: fec::Decoder,
+    to_write: VecDeque<Vec<u8>>,
+    /// Socket to receive from first.
+    next_sock: usize,
+}
+
+impl SockToTun {
+    /// Handle a decrypted message, queueing packets to be written to the tun
+    /// device. FEC messages are not expected inside FEC messages.
+    fn handle(&mut self,
+              common: &Common,
+              m: &[u8],
+              addr: &SocketAddr,
+              tos: Option<u8>,
+              allow_fec: bool) {
+        match message::parse(m) {
+            Some(Message::Packet(_)) |
+            Some(Message::Compressed { .. }) => {
+                match packet(m) {
+                    Some(p) => self.deliver(p, tos),
+                    None => debug!("invalid compressed message"),
+                }
+            }
+            Some(Message::Probe(size)) => {
+                let ack = common.encrypt(&message::probe_ack(size));
+                if let Err(e) = common.send_to(&ack, addr, None) {
+                    debug!("failed to send probe ack: {}", e);
+                }
+            }
+            Some(Message::ProbeAck(size)) => {
+                if size as u32 > self.pmtu_acked.get() {
+                    self.pmtu_acked.set(size as u32);
+                }
+            }
+            Some(Message::Fragment { id, index, count, data }) => {
+                if let Some(r) = self.reassembler.add(id, index, count, data, Instant::now()) {
+                    match packet(&r) {
+                        Some(p) => self.deliver(p, tos),
+                        None => debug!("reassembled message is not a packet"),
+                    }
+                }
+            }
+            Some(Message::Dummy) => {}
+            Some(Message::Padded(inner)) => {
+                if let Some(Message::Padded(_)) = message::parse(inner) {
+                    debug!("nested padding");
+                } else {
+                    self.handle(common, inner, addr, tos, allow_fec);
+                }
+            }
+            Some(Message::FecData { group, index, data }) if allow_fec => {
+                for m in self.fec.data(group, index, data, Instant::now()) {
+                    self.handle(common, &m, addr, tos, false);
+                }
+            }
+            Some(Message::FecParity { group, index, count, shard }) if allow_fec => {
+                for m in self.fec.parity(group, index, count, shard, Instant::now()) {
+                    self.handle(common, &m, addr, tos, false);
+                }
+                let mut stats = common.stats.borrow_mut();
+                stats.fec_recovered = self.fec.recovered;
+                stats.fec_lost = self.fec.lost;
+            }
+            _ => debug!("unknown message"),
+        }
+    }
+
+    fn deliver(&mut self, mut p: Vec<u8>, tos: Option<u8>) {
+        clamp_mss(self.mss_clamp, self.mtu.get(), &mut p);
+        if let Some(tos) = tos.and_then(|t| if self.ecn { Some(t) } else { None }) {
+            if !ip::decapsulate_ecn(&mut p, tos) {
+                debug!("dropping CE marked packet that is not ECN capable");
+                return;
+            }
+        }
+        self.to_write.push_back(p);
+    }
+}
+
+/// The packet in a message that is a packet or a compressed packet.
+fn packet(m: &[u8]) -> Option<Vec<u8>> {
+    match message::parse(m) {
+        Some(Message::Packet(_)) => Some(m.to_vec()),
+        Some(Message::Compressed { len, data }) => {
+            message::decompress(len, data).and_then(|p| match message::parse(&p) {
+                Some(Message::Packet(_)) => Some(p),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Clamp MSS of TCP SYN packets according to the config.
+fn clamp_mss(clamp: Option<MssClamp>, mtu: u32, p: &mut [u8]) {
+    let mss = match clamp {
+        None => return,
+        Some(MssClamp::Fixed(mss)) => mss,
+        Some(MssClamp::Auto) => {
+            // IP and TCP headers.
+            let headers = if ip::version(p) == Some(6) { 60 } else { 40 };
+            ::std::cmp::min(mtu.saturating_sub(headers), 0xffff) as u16
+        }
+    };
+    ip::clamp_mss(p, mss);
 }
 
 // poll and try_nb! are somewhat like async/await...only the function continues from the start,
@@ -136,33 +588,77 @@ impl Future for SockToTun {
     type Error = TiTunError;
 
     fn poll(&mut self) -> Poll<(), TiTunError> {
-        let mut common = self.common.borrow_mut();
+        let common = self.common.clone();
+        let mut common = common.borrow_mut();
         // Explicit deref_mut to get mutable references to disjoint fields.
         let mut common = common.deref_mut();
 
         // Do not loop forever, to avoid starvation. See
         // https://github.com/tokio-rs/tokio-core/issues/165
         for _ in 0..128 {
-            self.buf_to_write = if let Some(ref b) = self.buf_to_write {
-                try_nb!(common.tun.write(b.as_slice()));
-                None
-            } else {
-                None
+            while let Some(p) = self.to_write.pop_front() {
+                match common.tun.write(&p) {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.to_write.push_front(p);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        common.stats.borrow_mut().tun_write_errors += 1;
+                        debug!("failed to write to tun device: {}", e);
+                    }
+                }
+            }
+
+            // Receive from the sockets in turn.
+            let n = common.socks.len();
+            let mut received = None;
+            for i in 0..n {
+                let s = (self.next_sock + i) % n;
+                match common.socks[s].recv_from(common.buf.as_mut()) {
+                    Ok(r) => {
+                        received = Some((s, r));
+                        break;
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(TiTunError::network("Failed to receive", e)),
+                }
+            }
+            let (s, (l, addr, tos)) = match received {
+                Some(r) => r,
+                None => return Ok(Async::NotReady),
             };
+            self.next_sock = (s + 1) % n;
 
-            let (l, addr) = try_nb!(common.sock.recv_from(common.buf.as_mut()));
-            if let Some(p) = common.crypto.decrypt(common.buf[..l].as_ref()) {
-                if let Some(ref r) = self.remote_addr {
-                    let mut rr = r.borrow_mut();
-                    if *rr != Some(addr) {
-                        *rr = Some(addr);
-                        info!("Peer address set to {}", addr);
+            let p = match common.decrypt(common.buf[..l].as_ref()) {
+                Ok(p) => p,
+                Err(e) => {
+                    let mut stats = common.stats.borrow_mut();
+                    match e {
+                        DecryptError::Invalid => stats.decrypt_failures += 1,
+                        DecryptError::Timestamp => stats.timestamp_rejections += 1,
                     }
+                    debug!("decryption failed: {:?}", e);
+                    continue;
+                }
+            };
+            {
+                let mut stats = common.stats.borrow_mut();
+                stats.rx_packets += 1;
+                stats.rx_bytes += l as u64;
+                stats.rx_sizes.observe(l);
+            }
+            common.unanswered_since.set(None);
+            common.last_rx = Some(SystemTime::now());
+            common.reply_sock = s;
+            if let Some(ref r) = self.remote_addr {
+                let mut rr = r.borrow_mut();
+                if *rr != Some(addr) {
+                    *rr = Some(addr);
+                    info!("Peer address set to {}", addr);
                 }
-                self.buf_to_write = Some(p);
-            } else {
-                debug!("decryption failed");
             }
+            self.handle(common, &p, &addr, tos, true);
         }
 
         task::park().unpark();
@@ -173,7 +669,37 @@ impl Future for SockToTun {
 struct TunToSock {
     common: Rc<RefCell<Common>>,
     remote_addr: Rc<RefCell<Option<SocketAddr>>>,
-    buf_to_send: Option<Vec<u8>>,
+    scheduler: Scheduler,
+    copy_dscp: bool,
+    ecn: bool,
+    mss_clamp: Option<MssClamp>,
+    mtu: Rc<Cell<u32>>,
+    /// Fragment packets exceeding `mtu` instead of dropping them.
+    fragment: bool,
+    fragment_id: u32,
+    fec: Option<fec::Encoder>,
+    compress: bool,
+    padding: Option<Padding>,
+    /// Per packet overhead on top of `mtu`, reserved for FEC and padding.
+    overhead: usize,
+    /// Packets are sent by `CoverTraffic`, not here.
+    cover: bool,
+    // Encrypted packets and TOS of the outer packets.
+    to_send: Rc<RefCell<VecDeque<(Vec<u8>, Option<u8>)>>>,
+}
+
+impl TunToSock {
+    /// TOS of the outer packet, if it should be set at all.
+    fn outer_tos(&self, p: &[u8]) -> Option<u8> {
+        if !self.copy_dscp && !self.ecn {
+            return None;
+        }
+        let tc = ip::traffic_class(p).unwrap_or(0);
+        let dscp = if self.copy_dscp { tc & !3 } else { 0 };
+        // RFC 6040 normal mode: copy the ECN field.
+        let ecn = if self.ecn { tc & 3 } else { 0 };
+        Some(dscp | ecn)
+    }
 }
 
 impl Future for TunToSock {
@@ -186,20 +712,459 @@ impl Future for TunToSock {
         let mut common = common.deref_mut();
 
         for _ in 0..128 {
-            self.buf_to_send = if let Some(ref b) = self.buf_to_send {
+            let mut to_send = self.to_send.borrow_mut();
+            while let Some(&(ref b, tos)) = to_send.front() {
+                if self.cover {
+                    break;
+                }
                 if let Some(ref a) = *self.remote_addr.borrow() {
-                    try_nb!(common.sock.send_to(b.as_ref(), a));
+                    match common.send_to(b, a, tos) {
+                        Ok(_) => {}
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            return Ok(Async::NotReady)
+                        }
+                        // Larger than the path MTU when path MTU discovery is on.
+                        Err(ref e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                            debug!("packet too big for path MTU, dropped");
+                        }
+                        Err(e) => debug!("failed to send packet: {}", e),
+                    }
                 }
-                None
-            } else {
-                None
-            };
+                to_send.pop_front();
+            }
+            let cover_full = self.cover && to_send.len() >= COVER_QUEUE;
+            drop(to_send);
+
+            // Read what is available from the tun device, so that the
+            // scheduler has something to choose from.
+            let mut tun_ready = true;
+            for _ in 0..32 {
+                match common.tun.read(common.buf.as_mut()) {
+                    Ok(l) => {
+                        if !self.fragment &&
+                           drop_too_big(self.mtu.get(), &mut common.tun, &common.buf[..l]) {
+                            common.stats.borrow_mut().too_big += 1;
+                            continue;
+                        }
+                        clamp_mss(self.mss_clamp, self.mtu.get(), &mut common.buf[..l]);
+                        if !self.scheduler.enqueue(common.buf[..l].to_vec()) {
+                            common.stats.borrow_mut().queue_drops += 1;
+                        }
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        tun_ready = false;
+                        break;
+                    }
+                    Err(e) => return Err(TiTunError::device("Failed to read from tun device", e)),
+                }
+            }
+
+            if cover_full {
+                if !tun_ready {
+                    return Ok(Async::NotReady);
+                }
+                continue;
+            }
 
-            let l = try_nb!(common.tun.read(common.buf.as_mut()));
-            self.buf_to_send = Some(common.crypto.encrypt(common.buf[..l].as_ref()));
+            match self.scheduler.dequeue() {
+                Some(p) => {
+                    let tos = self.outer_tos(&p);
+                    let c = if self.compress { message::compress(&p) } else { None };
+                    let p = match c {
+                        Some(c) => {
+                            let mut stats = common.stats.borrow_mut();
+                            stats.compressed += 1;
+                            stats.compress_saved += (p.len() - c.len()) as u64;
+                            c
+                        }
+                        None => p,
+                    };
+                    let mtu = self.mtu.get() as usize;
+                    let messages = if self.fragment && p.len() > mtu {
+                        self.fragment_id = self.fragment_id.wrapping_add(1);
+                        match message::fragments(self.fragment_id, &p, mtu) {
+                            Some(fs) => fs,
+                            None => {
+                                debug!("packet too big to fragment, dropped");
+                                continue;
+                            }
+                        }
+                    } else {
+                        vec![p]
+                    };
+                    let max = mtu + self.overhead;
+                    for m in messages {
+                        match self.fec {
+                            Some(ref mut fec) => {
+                                let p = seal(common, &self.padding, max, &fec.data(&m));
+                                self.to_send.borrow_mut().push_back((p, tos));
+                                if fec.full() {
+                                    for p in fec.finish() {
+                                        let p = seal(common, &self.padding, max, &p);
+                                        self.to_send.borrow_mut().push_back((p, None));
+                                    }
+                                }
+                            }
+                            None => {
+                                let p = seal(common, &self.padding, max, &m);
+                                self.to_send.borrow_mut().push_back((p, tos));
+                            }
+                        }
+                    }
+                }
+                None if !tun_ready => {
+                    // Nothing more to send for now, finish the FEC group
+                    // instead of waiting for it to fill.
+                    if let Some(ref mut fec) = self.fec {
+                        let parity = fec.finish();
+                        if !parity.is_empty() {
+                            let max = self.mtu.get() as usize + self.overhead;
+                            for p in parity {
+                                let p = seal(common, &self.padding, max, &p);
+                                self.to_send.borrow_mut().push_back((p, None));
+                            }
+                            continue;
+                        }
+                    }
+                    return Ok(Async::NotReady);
+                }
+                None => {}
+            }
         }
 
         task::park().unpark();
         Ok(Async::NotReady)
     }
 }
+
+/// Pad a message according to the padding policy and encrypt it. `max` is
+/// the largest message that fits in one packet.
+fn seal(common: &Common, padding: &Option<Padding>, max: usize, m: &[u8]) -> Vec<u8> {
+    let len = m.len() + message::PADDING_HEADER_LEN;
+    let size = match *padding {
+        None => return common.encrypt(m),
+        Some(Padding::Mtu) => max,
+        Some(Padding::Buckets(ref buckets)) => {
+            buckets.iter().map(|&b| b as usize).find(|&b| b >= len).unwrap_or(len)
+        }
+        Some(Padding::Random) if len < max => {
+            let mut r = [0u8; 4];
+            randombytes_into(&mut r);
+            len + BigEndian::read_u32(&r) as usize % (max - len + 1)
+        }
+        Some(Padding::Random) => len,
+    };
+    common.encrypt(&message::pad(m, size))
+}
+
+/// If the packet is too big for the tunnel, write an ICMP packet too big
+/// message back to the tun device. Returns true if the packet should be
+/// dropped.
+fn drop_too_big(mut mtu: u32, tun: &mut PollEvented<Tun>, p: &[u8]) -> bool {
+    if ip::version(p) == Some(6) && mtu < MIN_MTU {
+        // Can't ask for less than the minimum, rely on outer fragmentation.
+        mtu = MIN_MTU;
+    }
+    if p.len() <= mtu as usize {
+        return false;
+    }
+    // IPv4 packets without DF are sent anyway.
+    let icmp = match ip::icmp_too_big(p, mtu) {
+        Some(icmp) => icmp,
+        None => return false,
+    };
+    if let Err(e) = tun.write(&icmp) {
+        debug!("failed to write ICMP packet too big: {}", e);
+    }
+    true
+}
+
+/// Sends packets to the peer at a constant rate, all of the same size. Real
+/// packets queued by `TunToSock` take the slots, dummy packets fill the
+/// rest.
+struct CoverTraffic {
+    common: Rc<RefCell<Common>>,
+    remote_addr: Rc<RefCell<Option<SocketAddr>>>,
+    queue: Rc<RefCell<VecDeque<(Vec<u8>, Option<u8>)>>>,
+    interval: Interval,
+    start: Instant,
+    /// Packets per second.
+    rate: u64,
+    /// Number of slots since `start`.
+    sent: u64,
+    /// Size of messages before encryption.
+    size: usize,
+}
+
+impl CoverTraffic {
+    fn tick(&mut self) -> Result<()> {
+        let elapsed = self.start.elapsed();
+        let due = elapsed.as_secs() * self.rate +
+                  elapsed.subsec_nanos() as u64 * self.rate / 1_000_000_000;
+        // Do not try to catch up on more than 100ms of missed slots.
+        let burst = self.rate / 10 + 1;
+        if due > self.sent + burst {
+            self.sent = due - burst;
+        }
+        while self.sent < due {
+            self.sent += 1;
+            self.send_slot()?;
+        }
+        Ok(())
+    }
+
+    fn send_slot(&self) -> Result<()> {
+        let common = self.common.borrow();
+        let (p, tos) = match self.queue.borrow_mut().pop_front() {
+            Some(x) => x,
+            None => (common.encrypt(&message::pad(&message::dummy(), self.size)), None),
+        };
+        let addr = match *self.remote_addr.borrow() {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        match common.send_to(&p, &addr, tos) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                debug!("socket not ready, cover traffic slot missed");
+                Ok(())
+            }
+            Err(e) => {
+                debug!("failed to send cover traffic: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Future for CoverTraffic {
+    type Item = ();
+    type Error = TiTunError;
+
+    fn poll(&mut self) -> Poll<(), TiTunError> {
+        loop {
+            match self.interval.poll()? {
+                Async::Ready(_) => self.tick()?,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Sends a dummy packet to the peer when nothing has been sent for
+/// `keepalive` seconds, to keep NAT and firewall state alive. Ticks once per
+/// second.
+struct KeepAlive {
+    common: Rc<RefCell<Common>>,
+    remote_addr: Rc<RefCell<Option<SocketAddr>>>,
+    config: Rc<RefCell<Config>>,
+    padding: Option<Padding>,
+    mtu: Rc<Cell<u32>>,
+    overhead: usize,
+    interval: Interval,
+}
+
+impl KeepAlive {
+    fn tick(&self) {
+        let keepalive = match self.config.borrow().keepalive {
+            Some(k) => Duration::from_secs(k),
+            None => return,
+        };
+        let addr = match *self.remote_addr.borrow() {
+            Some(a) => a,
+            None => return,
+        };
+        let common = self.common.borrow();
+        let idle = common.last_tx
+            .get()
+            .map_or(true, |t| t.elapsed().map(|e| e >= keepalive).unwrap_or(true));
+        if !idle {
+            return;
+        }
+        let max = self.mtu.get() as usize + self.overhead;
+        let p = seal(&common, &self.padding, max, &message::dummy());
+        if let Err(e) = common.send_to(&p, &addr, None) {
+            debug!("failed to send keepalive: {}", e);
+        }
+    }
+}
+
+impl Future for KeepAlive {
+    type Item = ();
+    type Error = TiTunError;
+
+    fn poll(&mut self) -> Poll<(), TiTunError> {
+        loop {
+            match self.interval.poll()? {
+                Async::Ready(_) => self.tick(),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Re-binds the UDP socket to a new random port, periodically or when the
+/// peer stops responding. Ticks once per second.
+struct PortRotation {
+    common: Rc<RefCell<Common>>,
+    handle: Handle,
+    /// Address to bind to, with port 0.
+    addr: SocketAddr,
+    config: Config,
+    interval: Interval,
+    last_rotation: Instant,
+}
+
+impl PortRotation {
+    fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let scheduled = self.config
+            .rotate_port
+            .map_or(false, |s| now.duration_since(self.last_rotation) >= Duration::from_secs(s));
+        let unanswered = self.common.borrow().unanswered_since.get();
+        let idle = match (self.config.rotate_port_idle, unanswered) {
+            (Some(s), Some(t)) => now.duration_since(t) >= Duration::from_secs(s),
+            _ => false,
+        };
+        if !scheduled && !idle {
+            return Ok(());
+        }
+
+        let sock = bind_socket(&self.addr, &self.config, &self.handle)?;
+        let mut common = self.common.borrow_mut();
+        info!("{}, rebind from {} to {}.",
+              if idle { "Peer not responding" } else { "Rotating port" },
+              common.socks[0].local_addr()?,
+              sock.local_addr()?);
+        common.socks[0] = sock;
+        common.unanswered_since.set(None);
+        self.last_rotation = now;
+        // Poll again so that the new socket is polled for reading.
+        task::park().unpark();
+        Ok(())
+    }
+}
+
+impl Future for PortRotation {
+    type Item = ();
+    type Error = TiTunError;
+
+    fn poll(&mut self) -> Poll<(), TiTunError> {
+        loop {
+            match self.interval.poll()? {
+                Async::Ready(_) => self.tick()?,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Probes the path MTU to the current peer. Ticks once per second, sending at
+/// most one probe per tick.
+struct PmtuDiscovery {
+    common: Rc<RefCell<Common>>,
+    remote_addr: Rc<RefCell<Option<SocketAddr>>>,
+    acked: Rc<Cell<u32>>,
+    interval: Interval,
+    /// The peer `search` is for.
+    peer: Option<SocketAddr>,
+    search: PmtuSearch,
+    max: u32,
+    idle_ticks: u32,
+    reported: Option<u32>,
+    /// Whether to set MTU of the tun device.
+    set_mtu: bool,
+    /// MTU of the tun device if it is not to be set.
+    tun_mtu: u32,
+    /// Per packet overhead on top of encryption, e.g. FEC and framing.
+    overhead: u32,
+    effective_mtu: Rc<Cell<u32>>,
+    config: Rc<RefCell<Config>>,
+    tun_name: String,
+}
+
+impl PmtuDiscovery {
+    fn tick(&mut self) {
+        let peer = *self.remote_addr.borrow();
+        if peer != self.peer {
+            self.peer = peer;
+            self.search = PmtuSearch::new(self.max, peer.map_or(false, |p| p.is_ipv6()));
+            self.idle_ticks = 0;
+            self.reported = None;
+        }
+        let peer = match peer {
+            Some(p) => p,
+            None => return,
+        };
+
+        if self.search.done() {
+            self.idle_ticks += 1;
+            if self.idle_ticks < PMTU_REPROBE_TICKS {
+                return;
+            }
+            self.idle_ticks = 0;
+            self.search.restart();
+        }
+
+        if let Some(size) = self.search.tick(self.acked.get()) {
+            self.acked.set(0);
+            self.send_probe(size, &peer);
+        }
+
+        if self.search.done() && self.reported != Some(self.search.pmtu()) {
+            self.report(&peer);
+        }
+    }
+
+    fn send_probe(&self, size: u32, peer: &SocketAddr) {
+        let common = self.common.borrow();
+        let len = size.saturating_sub(mtu::overhead(peer.is_ipv6()))
+            .saturating_sub(common.framer.overhead() as u32) as usize;
+        let probe = common.encrypt(&message::probe(size as u16, len));
+        if let Err(e) = common.send_to(&probe, peer, None) {
+            debug!("failed to send probe of size {}: {}", size, e);
+        }
+    }
+
+    fn report(&mut self, peer: &SocketAddr) {
+        let pmtu = self.search.pmtu();
+        self.reported = Some(pmtu);
+        let mtu = tun_mtu(pmtu, peer.is_ipv6()).saturating_sub(self.overhead);
+        info!("Path MTU to {} is {}.", peer, pmtu);
+
+        if self.set_mtu {
+            match self.common.borrow().tun.get_ref().set_mtu(mtu) {
+                Ok(_) => info!("MTU of {} set to {}.", self.tun_name, mtu),
+                Err(e) => warn!("Failed to set MTU of {}: {}", self.tun_name, e),
+            }
+            self.effective_mtu.set(mtu);
+        } else {
+            self.effective_mtu.set(::std::cmp::min(mtu, self.tun_mtu));
+        }
+        if let Some(ref on_change) = self.config.borrow().on_pmtu_change {
+            let r = ScriptRunner::new()
+                .env("TUN", &self.tun_name)
+                .env("PEER", peer.to_string())
+                .env("PMTU", pmtu.to_string())
+                .env("MTU", mtu.to_string())
+                .run(on_change.as_bytes());
+            if let Err(e) = r {
+                warn!("on_pmtu_change: {}", e);
+            }
+        }
+    }
+}
+
+impl Future for PmtuDiscovery {
+    type Item = ();
+    type Error = TiTunError;
+
+    fn poll(&mut self) -> Poll<(), TiTunError> {
+        loop {
+            match self.interval.poll()? {
+                Async::Ready(_) => self.tick(),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
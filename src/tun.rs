@@ -18,7 +18,7 @@
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio::unix::EventedFd;
 use nix::fcntl::{self, FcntlArg, OFlag, fcntl, open};
-use nix::libc::{c_int, c_short};
+use nix::libc::{self, c_int, c_short, c_ulong};
 use nix::sys::stat::Mode;
 use nix::unistd::{close, read, write};
 use std::ffi::{CStr, CString};
@@ -31,12 +31,23 @@ ioctl!(write tunsetiff with b'T', 202; c_int);
 const IFF_TUN: c_short = 0x0001;
 const IFF_NO_PI: c_short = 0x1000;
 
+const SIOCGIFMTU: c_ulong = 0x8921;
+const SIOCSIFMTU: c_ulong = 0x8922;
+
 #[repr(C)]
 struct ifreq {
     name: [u8; 16], // Use u8 becuase that's what CString and CStr wants.
     flags: c_short,
 }
 
+#[repr(C)]
+struct ifreq_mtu {
+    name: [u8; 16],
+    mtu: c_int,
+    // Pad to size of struct ifreq.
+    _pad: [u8; 20],
+}
+
 /// A linux tun device.
 #[derive(Debug)]
 pub struct Tun {
@@ -111,6 +122,37 @@ impl Tun {
         fcntl(self.fd, FcntlArg::F_SETFL(flags))?;
         Ok(())
     }
+
+    /// Do an interface ioctl that takes an `ifreq_mtu`. These work on any
+    /// socket, not on the tun fd itself.
+    fn mtu_ioctl(&self, request: c_ulong, mtu: c_int) -> Result<c_int> {
+        let mut ifr = ifreq_mtu {
+            name: [0; 16],
+            mtu: mtu,
+            _pad: [0; 20],
+        };
+        ifr.name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+
+        let sock =
+            unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if sock < 0 {
+            return Err(Error::last_os_error());
+        }
+        let r = unsafe { libc::ioctl(sock, request, &mut ifr as *mut ifreq_mtu) };
+        let e = Error::last_os_error();
+        let _ = close(sock);
+        if r < 0 { Err(e) } else { Ok(ifr.mtu) }
+    }
+
+    /// Get MTU of this device.
+    pub fn get_mtu(&self) -> Result<u32> {
+        self.mtu_ioctl(SIOCGIFMTU, 0).map(|m| m as u32)
+    }
+
+    /// Set MTU of this device.
+    pub fn set_mtu(&self, mtu: u32) -> Result<()> {
+        self.mtu_ioctl(SIOCSIFMTU, mtu as c_int).map(|_| ())
+    }
 }
 
 impl AsRawFd for Tun {
@@ -0,0 +1,306 @@
+// Copyright 2017 Sopium
+
+// This file is part of TiTun.
+
+// TiTun is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// TiTun is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with TiTun.  If not, see <https://www.gnu.org/licenses/>.
+
+use byteorder::{ByteOrder, NativeEndian};
+use futures::Async;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use nix::libc::{self, c_int, c_void, socklen_t};
+use std::cell::Cell;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio_core::reactor::{Handle, PollEvented};
+
+const IP_TOS: c_int = 1;
+const IP_MTU_DISCOVER: c_int = 10;
+const IP_RECVTOS: c_int = 13;
+const IPV6_MTU_DISCOVER: c_int = 23;
+const IPV6_RECVTCLASS: c_int = 66;
+const IPV6_TCLASS: c_int = 67;
+
+/// Set DF, but do not use or update the kernel's path MTU estimate.
+const IP_PMTUDISC_PROBE: c_int = 3;
+
+/// A non-blocking UDP socket that can set the traffic class of sent packets
+/// and report the traffic class of received packets.
+pub struct Socket {
+    sock: net::UdpSocket,
+    v6: bool,
+}
+
+impl Socket {
+    pub fn bind(addr: &SocketAddr) -> Result<Socket> {
+        let sock = net::UdpSocket::bind(addr)?;
+        sock.set_nonblocking(true)?;
+        Ok(Socket {
+            sock: sock,
+            v6: addr.is_ipv6(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    pub fn send_to(&self, buf: &[u8], target: &SocketAddr) -> Result<usize> {
+        self.sock.send_to(buf, target)
+    }
+
+    /// Set TOS / traffic class of subsequently sent packets.
+    pub fn set_tos(&self, tos: u8) -> Result<()> {
+        if self.v6 {
+            // IP_TOS is still used for IPv4-mapped destinations. Ignore errors.
+            let _ = self.setsockopt(libc::IPPROTO_IP, IP_TOS, tos as c_int);
+            self.setsockopt(libc::IPPROTO_IPV6, IPV6_TCLASS, tos as c_int)
+        } else {
+            self.setsockopt(libc::IPPROTO_IP, IP_TOS, tos as c_int)
+        }
+    }
+
+    /// Ask the kernel to report TOS / traffic class of received packets.
+    pub fn set_recv_tos(&self, on: bool) -> Result<()> {
+        if self.v6 {
+            let _ = self.setsockopt(libc::IPPROTO_IP, IP_RECVTOS, on as c_int);
+            self.setsockopt(libc::IPPROTO_IPV6, IPV6_RECVTCLASS, on as c_int)
+        } else {
+            self.setsockopt(libc::IPPROTO_IP, IP_RECVTOS, on as c_int)
+        }
+    }
+
+    /// Set the don't fragment flag, for path MTU discovery done by ourself.
+    /// Sending packets larger than the local interface MTU fails with
+    /// `EMSGSIZE`.
+    pub fn set_pmtu_probe(&self) -> Result<()> {
+        if self.v6 {
+            let _ = self.setsockopt(libc::IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_PROBE);
+            self.setsockopt(libc::IPPROTO_IPV6, IPV6_MTU_DISCOVER, IP_PMTUDISC_PROBE)
+        } else {
+            self.setsockopt(libc::IPPROTO_IP, IP_MTU_DISCOVER, IP_PMTUDISC_PROBE)
+        }
+    }
+
+    fn setsockopt(&self, level: c_int, name: c_int, val: c_int) -> Result<()> {
+        let r = unsafe {
+            libc::setsockopt(self.sock.as_raw_fd(),
+                             level,
+                             name,
+                             &val as *const c_int as *const c_void,
+                             mem::size_of::<c_int>() as socklen_t)
+        };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(Error::last_os_error())
+        }
+    }
+
+    /// Receive a packet. Also returns the TOS / traffic class of the packet,
+    /// if `set_recv_tos` is on.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr, Option<u8>)> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        // u64 for alignment.
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = mem::size_of_val(&control);
+
+        let l = unsafe { libc::recvmsg(self.sock.as_raw_fd(), &mut msg, 0) };
+        if l < 0 {
+            return Err(Error::last_os_error());
+        }
+        let addr = to_socket_addr(&addr)?;
+
+        let control: &[u8] = unsafe {
+            ::std::slice::from_raw_parts(control.as_ptr() as *const u8, msg.msg_controllen)
+        };
+        Ok((l as usize, addr, parse_tos(control)))
+    }
+}
+
+fn cmsg_align(len: usize) -> usize {
+    let a = mem::size_of::<usize>() - 1;
+    (len + a) & !a
+}
+
+/// Find the IP_TOS or IPV6_TCLASS control message.
+fn parse_tos(mut control: &[u8]) -> Option<u8> {
+    let hdr_len = mem::size_of::<libc::cmsghdr>();
+    while control.len() >= hdr_len {
+        let hdr: libc::cmsghdr = unsafe { ::std::ptr::read(control.as_ptr() as *const _) };
+        let len = hdr.cmsg_len as usize;
+        if len < hdr_len || len > control.len() {
+            return None;
+        }
+        let data = &control[cmsg_align(hdr_len)..len];
+        match (hdr.cmsg_level, hdr.cmsg_type) {
+            (libc::IPPROTO_IP, IP_TOS) if !data.is_empty() => return Some(data[0]),
+            // An int.
+            (libc::IPPROTO_IPV6, IPV6_TCLASS) if data.len() >= 4 => {
+                return Some(NativeEndian::read_i32(data) as u8)
+            }
+            _ => {}
+        }
+        control = &control[::std::cmp::min(cmsg_align(len), control.len())..];
+    }
+    None
+}
+
+fn to_socket_addr(a: &libc::sockaddr_storage) -> Result<SocketAddr> {
+    match a.ss_family as c_int {
+        libc::AF_INET => {
+            let a: &libc::sockaddr_in = unsafe { mem::transmute(a) };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(a.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let a: &libc::sockaddr_in6 = unsafe { mem::transmute(a) };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                u16::from_be(a.sin6_port),
+                                                a.sin6_flowinfo,
+                                                a.sin6_scope_id)))
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, "unknown address family")),
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Evented for Socket {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
+}
+
+/// A `Socket` registered with a tokio reactor. Like
+/// `tokio_core::net::UdpSocket`, operations return `WouldBlock` and schedule
+/// the current task to be notified when they can't make progress.
+pub struct UdpSocket {
+    io: PollEvented<Socket>,
+    tos: Cell<Option<u8>>,
+}
+
+impl UdpSocket {
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> Result<UdpSocket> {
+        Ok(UdpSocket {
+            io: PollEvented::new(Socket::bind(addr)?, handle)?,
+            tos: Cell::new(None),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+
+    pub fn set_recv_tos(&self, on: bool) -> Result<()> {
+        self.io.get_ref().set_recv_tos(on)
+    }
+
+    pub fn set_pmtu_probe(&self) -> Result<()> {
+        self.io.get_ref().set_pmtu_probe()
+    }
+
+    pub fn send_to(&self, buf: &[u8], target: &SocketAddr) -> Result<usize> {
+        if let Async::NotReady = self.io.poll_write() {
+            return Err(would_block());
+        }
+        let r = self.io.get_ref().send_to(buf, target);
+        if is_would_block(&r) {
+            self.io.need_write();
+        }
+        r
+    }
+
+    /// Send with the given TOS / traffic class. The socket option is only
+    /// changed when it differs from the previous packet.
+    pub fn send_to_with_tos(&self, buf: &[u8], target: &SocketAddr, tos: u8) -> Result<usize> {
+        if self.tos.get() != Some(tos) {
+            self.io.get_ref().set_tos(tos)?;
+            self.tos.set(Some(tos));
+        }
+        self.send_to(buf, target)
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr, Option<u8>)> {
+        if let Async::NotReady = self.io.poll_read() {
+            return Err(would_block());
+        }
+        let r = self.io.get_ref().recv_from(buf);
+        if is_would_block(&r) {
+            self.io.need_read();
+        }
+        r
+    }
+}
+
+fn would_block() -> Error {
+    Error::new(ErrorKind::WouldBlock, "would block")
+}
+
+fn is_would_block<T>(r: &Result<T>) -> bool {
+    match *r {
+        Err(ref e) => e.kind() == ErrorKind::WouldBlock,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_tos() {
+        let a = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = Socket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        b.set_recv_tos(true).unwrap();
+        a.set_tos(0xb9).unwrap();
+        a.send_to(&[1, 2, 3], &b.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (l, addr, tos) = loop {
+            match b.recv_from(&mut buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                r => break r.unwrap(),
+            }
+        };
+        assert_eq!(&buf[..l], &[1, 2, 3]);
+        assert_eq!(addr, a.local_addr().unwrap());
+        assert_eq!(tos, Some(0xb9));
+    }
+}